@@ -0,0 +1,279 @@
+use crate::error::Error;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, RequestBuilder, Response};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+
+pub mod binary;
+pub mod chunks;
+pub mod ftp;
+pub mod multipart;
+pub mod s3;
+pub mod sftp;
+pub mod tus;
+pub mod webdav;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+pub type ByteStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+/// The file as the target sees it.
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    pub path: PathBuf,
+    /// Upload name, with the .enc suffix when encrypted.
+    pub name: String,
+    /// The file below the directory it was found in, for targets that keep the tree.
+    pub relative_path: PathBuf,
+    /// Bytes that will be sent, the envelope size when encrypted.
+    pub size: u64,
+    /// Bytes sent in each part but the last, for chunked uploads; tag included when encrypted.
+    pub chunk_size: Option<u64>,
+    /// Envelope header sent in front of the first part, 0 when not encrypted.
+    pub header_size: u64,
+    /// A later run sends the same bytes again, false when sealed with a fresh key.
+    pub resumable: bool,
+    /// Passes [`Notice`]s about the upload on to the observers of the uploader.
+    pub reporter: Reporter,
+}
+
+/// What a backend has to say about an upload besides its result.
+#[derive(Debug)]
+pub enum Notice<'a> {
+    /// Something that doesn't fail the upload, e.g. a chunk sent again.
+    Warning(&'a str),
+    /// The target keeps the failed upload, `location` when the next run continues from there.
+    Resumable { location: Option<&'a str> },
+}
+
+/// Turns [`Notice`]s into [`Event`](crate::Event)s, does nothing for a [`FileInfo`] made outside an uploader.
+#[derive(Clone, Default)]
+pub struct Reporter(Option<Arc<ReportFn>>);
+
+type ReportFn = dyn Fn(Notice) + Send + Sync;
+
+impl Reporter {
+    /// Calls `report` for every notice, from the task that sends the file.
+    pub fn new(report: impl Fn(Notice) + Send + Sync + 'static) -> Self {
+        Self(Some(Arc::new(report)))
+    }
+
+    /// Hands `notice` to the observers.
+    pub fn report(&self, notice: Notice) {
+        if let Some(report) = &self.0 {
+            report(notice);
+        }
+    }
+
+    /// Reports a [`Notice::Warning`].
+    pub fn warn(&self, message: &str) {
+        self.report(Notice::Warning(message));
+    }
+}
+
+impl std::fmt::Debug for Reporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Reporter").field(&self.0.is_some()).finish()
+    }
+}
+
+/// State shared by all parts of one upload, e.g. a server side upload id.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    /// The upload id or URL the target handed out in `init`.
+    pub id: Option<String>,
+    /// Where the file ends up, e.g. the remote path.
+    pub location: Option<String>,
+    /// Bytes the target already holds from an interrupted upload, parts
+    /// that end below it are not sent again.
+    pub offset: u64,
+}
+
+/// The data of a [`Part`].
+pub enum PartBody {
+    /// One chunk, read into memory.
+    Bytes(Bytes),
+    /// The whole file, for backends without a chunk size.
+    Stream(ByteStream),
+}
+
+/// A piece of a file for [`Backend::put_part`], the whole file when the backend has no chunk size.
+pub struct Part {
+    /// Position of the part, from 0.
+    pub index: u64,
+    pub total: u64,
+    /// Byte offset of this part in the uploaded data.
+    pub offset: u64,
+    /// Bytes in `body`.
+    pub len: u64,
+    pub body: PartBody,
+}
+
+/// What the target answered to a part, handed to [`Backend::complete`].
+#[derive(Debug, Clone, Default)]
+pub struct PartReceipt {
+    pub index: u64,
+    pub etag: Option<String>,
+    /// Response body, e.g. the download link for single part uploads.
+    pub response: Option<String>,
+}
+
+/// One upload target; [`Uploader`](crate::Uploader) drives every file through
+/// `init` -> `put_part` (once per part) -> `complete`, and `abort` when anything fails.
+#[async_trait]
+pub trait Backend: Send + Sync + std::fmt::Debug {
+    /// Splits a file of `file_size` bytes into parts of this size, or streams it as
+    /// one part with `None`; `preferred` is the pick of `--chunk-size auto`.
+    fn chunk_size(&self, _file_size: u64, _preferred: Option<usize>) -> Option<usize> {
+        None
+    }
+
+    /// Parts of one file that may be in flight at the same time.
+    fn max_concurrency(&self) -> usize {
+        1
+    }
+
+    /// Starts the upload of `file`, e.g. asks the server for an upload id.
+    async fn init(&self, _file: &FileInfo) -> Result<Session, BoxError> {
+        Ok(Session::default())
+    }
+
+    /// Sends one part, parts of a file can be in flight at the same time up to `max_concurrency`.
+    async fn put_part(&self, file: &FileInfo, session: &Session, part: Part) -> Result<PartReceipt, BoxError>;
+
+    /// Finishes the upload once all parts are sent, returns what the user should see, e.g. a download link.
+    async fn complete(
+        &self,
+        _file: &FileInfo,
+        _session: &Session,
+        receipts: Vec<PartReceipt>,
+    ) -> Result<Option<String>, BoxError> {
+        Ok(receipts.into_iter().last().and_then(|receipt| receipt.response))
+    }
+
+    /// Cleans up after a failed or cancelled upload, or keeps it for the next run to resume.
+    async fn abort(&self, _file: &FileInfo, _session: &Session) -> Result<(), BoxError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum BackendKind {
+    #[default]
+    Multipart,
+    Binary,
+    Chunks,
+    /// Chunks in the original base64 JSON format.
+    JsonChunks,
+    S3,
+    Tus,
+    WebDav,
+    Sftp,
+    Ftp,
+}
+
+impl std::str::FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "multipart" => Ok(BackendKind::Multipart),
+            "binary" => Ok(BackendKind::Binary),
+            "chunks" => Ok(BackendKind::Chunks),
+            "json-chunks" => Ok(BackendKind::JsonChunks),
+            "s3" => Ok(BackendKind::S3),
+            "tus" => Ok(BackendKind::Tus),
+            "webdav" => Ok(BackendKind::WebDav),
+            "sftp" => Ok(BackendKind::Sftp),
+            "ftp" => Ok(BackendKind::Ftp),
+            _ => Err("Invalid backend, expected multipart, binary, chunks, json-chunks, s3, tus, webdav, sftp or ftp".into()),
+        }
+    }
+}
+
+/// An HTTP endpoint shared by the HTTP based backends.
+#[derive(Debug, Clone)]
+pub struct HttpTarget {
+    pub client: Client,
+    pub url: Arc<str>,
+    pub headers: HeaderMap,
+}
+
+impl HttpTarget {
+    pub fn new(client: Client, url: &str) -> Self {
+        Self {
+            client,
+            url: url.into(),
+            headers: HeaderMap::new(),
+        }
+    }
+
+    pub fn post(&self) -> RequestBuilder {
+        self.client.post(&*self.url).headers(self.headers.clone())
+    }
+}
+
+pub fn part_body(body: PartBody) -> reqwest::Body {
+    match body {
+        PartBody::Bytes(bytes) => reqwest::Body::from(bytes),
+        PartBody::Stream(stream) => reqwest::Body::wrap_stream(stream),
+    }
+}
+
+pub async fn check_status(response: Response) -> Result<Response, BoxError> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        let status = response.status();
+        Err(Error::from_status(status, response.text().await.unwrap_or_default()).into())
+    }
+}
+
+/// User names and paths taken from URLs.
+pub fn percent_decode(value: &str) -> String {
+    percent_encoding::percent_decode_str(value).decode_utf8_lossy().to_string()
+}
+
+/// Settings of the individual backends, filled from the command line.
+#[derive(Debug, Clone, Default)]
+pub struct BackendOptions {
+    pub chunk_size: Option<usize>,
+    pub chunk_format: chunks::ChunkFormat,
+    pub chunk_session: Option<chunks::SessionPaths>,
+    pub s3: s3::S3Options,
+    pub tus: tus::TusOptions,
+    pub webdav: webdav::WebDavOptions,
+    pub sftp: sftp::SftpOptions,
+    pub ftp: ftp::FtpOptions,
+}
+
+pub fn build(kind: &BackendKind, target: HttpTarget, options: &BackendOptions) -> Result<Arc<dyn Backend>, BoxError> {
+    Ok(match kind {
+        BackendKind::Multipart => Arc::new(multipart::MultipartBackend::new(target)),
+        BackendKind::Binary => Arc::new(binary::BinaryBackend::new(target)),
+        BackendKind::Chunks => Arc::new(chunks::ChunksBackend::new(
+            target,
+            options.chunk_size.unwrap_or(chunks::DEFAULT_CHUNK_SIZE),
+            options.chunk_format,
+            options.chunk_session.clone(),
+        )),
+        BackendKind::JsonChunks => Arc::new(chunks::ChunksBackend::new(
+            target,
+            options.chunk_size.unwrap_or(chunks::DEFAULT_CHUNK_SIZE),
+            chunks::ChunkFormat::Json,
+            options.chunk_session.clone(),
+        )),
+        BackendKind::S3 => Arc::new(s3::S3Backend::new(target, options.s3.clone(), options.chunk_size)?),
+        BackendKind::Tus => Arc::new(tus::TusBackend::new(
+            target,
+            options.chunk_size.unwrap_or(tus::DEFAULT_CHUNK_SIZE),
+            options.tus.clone(),
+        )),
+        BackendKind::WebDav => Arc::new(webdav::WebDavBackend::new(target, options.webdav.clone())?),
+        BackendKind::Sftp => Arc::new(sftp::SftpBackend::new(target, options.sftp.clone())?),
+        BackendKind::Ftp => Arc::new(ftp::FtpBackend::new(target, options.ftp.clone())?),
+    })
+}
@@ -0,0 +1,37 @@
+use crate::backend::{check_status, part_body, Backend, BoxError, FileInfo, HttpTarget, Part, PartReceipt, Session};
+use async_trait::async_trait;
+use reqwest::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+
+// the raw file as the request body, the name goes in X-Filename
+#[derive(Debug)]
+pub struct BinaryBackend {
+    target: HttpTarget,
+}
+
+impl BinaryBackend {
+    pub fn new(target: HttpTarget) -> Self {
+        Self { target }
+    }
+}
+
+#[async_trait]
+impl Backend for BinaryBackend {
+    async fn put_part(&self, file: &FileInfo, _session: &Session, part: Part) -> Result<PartReceipt, BoxError> {
+        let request = self
+            .target
+            .post()
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header("X-Filename", HeaderValue::from_str(&file.name)?)
+            // streamed bodies would otherwise go out with chunked encoding
+            .header(CONTENT_LENGTH, part.len)
+            .body(part_body(part.body));
+
+        let response = check_status(request.send().await?).await?;
+
+        Ok(PartReceipt {
+            index: part.index,
+            response: Some(response.text().await?),
+            ..Default::default()
+        })
+    }
+}
@@ -0,0 +1,269 @@
+use crate::backend::{check_status, Backend, BoxError, FileInfo, HttpTarget, Part, PartBody, PartReceipt, Session};
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine};
+use reqwest::header::{HeaderValue, CONTENT_RANGE, CONTENT_TYPE};
+use reqwest::{multipart, RequestBuilder, Url};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+const MAX_CONCURRENT_UPLOADS: usize = 32;
+
+// how a chunk travels in its POST
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ChunkFormat {
+    // the chunk as the body, everything else in headers
+    #[default]
+    Raw,
+    // the chunk as the `data` field of a multipart/form-data body
+    Multipart,
+    // the original format, base64 in JSON: a third larger and copied once more
+    Json,
+}
+
+impl std::str::FromStr for ChunkFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(ChunkFormat::Raw),
+            "multipart" => Ok(ChunkFormat::Multipart),
+            "json" => Ok(ChunkFormat::Json),
+            _ => Err("Invalid chunk format, expected raw, multipart or json".into()),
+        }
+    }
+}
+
+// endpoints of the init -> chunks -> finalize handshake, below --host unless
+// given as full URLs
+#[derive(Debug, Clone)]
+pub struct SessionPaths {
+    pub init: String,
+    pub finalize: String,
+    pub abort: String,
+}
+
+impl Default for SessionPaths {
+    fn default() -> Self {
+        Self {
+            init: "init".to_string(),
+            finalize: "finalize".to_string(),
+            abort: "abort".to_string(),
+        }
+    }
+}
+
+// one POST per chunk, the server reassembles the file from the chunk id and
+// the total number of chunks; with session paths the upload is opened first
+// and every chunk carries its id, so uploads of the same name stay apart and
+// the server learns when a file is complete
+#[derive(Debug)]
+pub struct ChunksBackend {
+    target: HttpTarget,
+    chunk_size: usize,
+    format: ChunkFormat,
+    session_paths: Option<SessionPaths>,
+}
+
+impl ChunksBackend {
+    pub fn new(target: HttpTarget, chunk_size: usize, format: ChunkFormat, session_paths: Option<SessionPaths>) -> Self {
+        Self {
+            target,
+            chunk_size,
+            format,
+            session_paths,
+        }
+    }
+
+    fn endpoint(&self, path: &str) -> Result<Url, BoxError> {
+        if let Ok(url) = Url::parse(path) {
+            return Ok(url);
+        }
+        let mut url = Url::parse(&self.target.url)?;
+        url.path_segments_mut()
+            .map_err(|_| format!("Invalid URL {}", self.target.url))?
+            .pop_if_empty()
+            .extend(path.split('/').filter(|segment| !segment.is_empty()));
+        Ok(url)
+    }
+
+    async fn post_json(&self, path: &str, payload: &Value) -> Result<String, BoxError> {
+        let url = self.endpoint(path)?;
+        let request = self.target.client.post(url).headers(self.target.headers.clone()).json(payload);
+        Ok(check_status(request.send().await?).await?.text().await?)
+    }
+
+    // X-Filename, X-Chunk-Id, X-Total-Chunks and Content-Range, plus
+    // X-Upload-Id once the server assigned one
+    fn raw(&self, file: &FileInfo, session: &Session, part: &Part, checksum: &str) -> Result<RequestBuilder, BoxError> {
+        let mut request = self
+            .target
+            .post()
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header("X-Filename", HeaderValue::from_str(&file.name)?)
+            .header("X-Chunk-Id", part.index)
+            .header("X-Total-Chunks", part.total)
+            .header(CONTENT_RANGE, content_range(part, file.size))
+            .header("X-Chunk-Sha256", checksum);
+        if let Some(id) = &session.id {
+            request = request.header("X-Upload-Id", id);
+        }
+        Ok(request)
+    }
+
+    fn multipart(&self, file: &FileInfo, session: &Session, part: &Part, checksum: &str) -> multipart::Form {
+        let mut form = multipart::Form::new()
+            .text("filename", file.name.clone())
+            .text("chunk_id", part.index.to_string())
+            .text("total_chunks", part.total.to_string())
+            .text("offset", part.offset.to_string())
+            .text("sha256", checksum.to_string());
+        if let Some(id) = &session.id {
+            form = form.text("upload_id", id.clone());
+        }
+        form
+    }
+}
+
+#[async_trait]
+impl Backend for ChunksBackend {
+    fn chunk_size(&self, _file_size: u64, preferred: Option<usize>) -> Option<usize> {
+        Some(preferred.unwrap_or(self.chunk_size))
+    }
+
+    fn max_concurrency(&self) -> usize {
+        MAX_CONCURRENT_UPLOADS
+    }
+
+    async fn init(&self, file: &FileInfo) -> Result<Session, BoxError> {
+        let Some(paths) = &self.session_paths else {
+            return Ok(Session::default());
+        };
+
+        let mut payload = json!({
+            "filename": file.name,
+            "size": file.size,
+            "chunk_size": file.chunk_size,
+        });
+        // the first chunk of an encrypted file also carries the envelope header
+        if file.header_size > 0 {
+            payload["first_chunk_size"] = Value::from(file.chunk_size.map(|size| size + file.header_size));
+        }
+        let response = self.post_json(&paths.init, &payload).await?;
+
+        // {"upload_id": "..."}, {"id": "..."} or just the id
+        let id = match serde_json::from_str::<Value>(&response) {
+            Ok(Value::Object(body)) => body
+                .get("upload_id")
+                .or_else(|| body.get("id"))
+                .map(|id| id.as_str().map(str::to_string).unwrap_or_else(|| id.to_string())),
+            _ => Some(response.trim().to_string()).filter(|id| !id.is_empty()),
+        };
+        let id = id.ok_or("The server returned no upload id")?;
+
+        Ok(Session {
+            id: Some(id),
+            ..Default::default()
+        })
+    }
+
+    async fn put_part(&self, file: &FileInfo, session: &Session, part: Part) -> Result<PartReceipt, BoxError> {
+        let PartBody::Bytes(ref data) = part.body else {
+            return Err("Chunked uploads need the chunk in memory".into());
+        };
+        let data = data.clone();
+        // lets the server check each chunk, and finalize check the whole file
+        let checksum = hex::encode(Sha256::digest(&data));
+
+        let request = match self.format {
+            ChunkFormat::Raw => self.raw(file, session, &part, &checksum)?.body(data),
+            ChunkFormat::Multipart => {
+                let chunk = multipart::Part::stream_with_length(data, part.len)
+                    .file_name(file.name.clone())
+                    .mime_str("application/octet-stream")?;
+                self.target
+                    .post()
+                    .multipart(self.multipart(file, session, &part, &checksum).part("data", chunk))
+            }
+            ChunkFormat::Json => {
+                // Encode chunk in Base64 (required for JSON compatibility)
+                let mut payload = json!({
+                    "filename": file.name,
+                    "chunk_id": part.index,
+                    "total_chunks": part.total,
+                    "data": general_purpose::STANDARD.encode(&data),
+                });
+                if let Some(id) = &session.id {
+                    payload["upload_id"] = Value::from(id.as_str());
+                }
+                self.target.post().json(&payload)
+            }
+        };
+
+        let response = check_status(request.send().await?).await?;
+
+        Ok(PartReceipt {
+            index: part.index,
+            etag: Some(checksum),
+            response: Some(response.text().await?),
+        })
+    }
+
+    async fn complete(
+        &self,
+        file: &FileInfo,
+        session: &Session,
+        receipts: Vec<PartReceipt>,
+    ) -> Result<Option<String>, BoxError> {
+        let (Some(paths), Some(id)) = (&self.session_paths, &session.id) else {
+            // the server answers every chunk, there is no download link for the file
+            return Ok(None);
+        };
+
+        let checksums: Vec<String> = receipts.into_iter().filter_map(|receipt| receipt.etag).collect();
+        let mut payload = json!({
+            "upload_id": id,
+            "filename": file.name,
+            "size": file.size,
+            "total_chunks": checksums.len(),
+            "chunk_sha256": checksums,
+        });
+        // sealed files differ from what is on disk, their chunks are still checked
+        if file.resumable {
+            payload["sha256"] = Value::from(file_checksum(file).await?);
+        }
+
+        let response = self.post_json(&paths.finalize, &payload).await?;
+        Ok(Some(response).filter(|response| !response.trim().is_empty()))
+    }
+
+    async fn abort(&self, file: &FileInfo, session: &Session) -> Result<(), BoxError> {
+        let (Some(paths), Some(id)) = (&self.session_paths, &session.id) else {
+            return Ok(());
+        };
+        let payload = json!({
+            "upload_id": id,
+            "filename": file.name,
+        });
+        self.post_json(&paths.abort, &payload).await?;
+        Ok(())
+    }
+}
+
+async fn file_checksum(file: &FileInfo) -> Result<String, BoxError> {
+    let path = file.path.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+        Ok(hex::encode(hasher.finalize()))
+    })
+    .await?
+}
+
+// `bytes 0-1023/4096`, or `bytes */0` for the single chunk of an empty file
+fn content_range(part: &Part, size: u64) -> String {
+    match part.len {
+        0 => format!("bytes */{}", size),
+        len => format!("bytes {}-{}/{}", part.offset, part.offset + len - 1, size),
+    }
+}
@@ -0,0 +1,540 @@
+use crate::backend::{
+    percent_decode, Backend, BoxError, ByteStream, FileInfo, HttpTarget, Notice, Part, PartBody, PartReceipt, Session,
+};
+use crate::error::Error;
+use crate::tls::TlsOptions;
+use async_trait::async_trait;
+use futures::StreamExt;
+use rustls::pki_types::ServerName;
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::{Component, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+const DEFAULT_PORT: u16 = 21;
+
+#[derive(Debug, Clone, Default)]
+pub struct FtpOptions {
+    // anonymous when neither the URL nor --user has one
+    pub user: Option<String>,
+    pub password: Option<String>,
+    // AUTH TLS on the control connection and TLS on every data connection
+    pub explicit_tls: bool,
+    pub tls: TlsOptions,
+    pub connect_timeout: Option<Duration>,
+    // where interrupted uploads are recorded, so the next run continues them
+    // with REST / APPE; partial files are deleted and never continued when unset
+    pub state_file: Option<PathBuf>,
+}
+
+impl FtpOptions {
+    pub fn default_state_file() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("dup-cli").join("ftp.json"))
+    }
+}
+
+// a connection before and after AUTH TLS
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(&mut **stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(&mut **stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(&mut **stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(&mut **stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// a logged in control connection
+struct Control {
+    stream: BufReader<Stream>,
+    // data connections go to the same server, whatever address PASV reports
+    peer: IpAddr,
+}
+
+impl Control {
+    async fn read_reply(&mut self) -> Result<(u16, String), BoxError> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err("The FTP server closed the connection".into());
+        }
+        let code: u16 = line
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| format!("Invalid FTP reply: {}", line.trim_end()))?;
+        let mut text = line[3..].to_string();
+
+        // multi-line replies end with the code followed by a space
+        if line.as_bytes().get(3) == Some(&b'-') {
+            let last = format!("{} ", code);
+            loop {
+                line.clear();
+                if self.stream.read_line(&mut line).await? == 0 {
+                    return Err("The FTP server closed the connection".into());
+                }
+                text.push_str(&line);
+                if line.starts_with(&last) {
+                    break;
+                }
+            }
+        }
+
+        Ok((code, text.trim_start_matches(['-', ' ']).trim_end().to_string()))
+    }
+
+    async fn command(&mut self, command: &str) -> Result<(u16, String), BoxError> {
+        self.stream.write_all(format!("{}\r\n", command).as_bytes()).await?;
+        self.stream.flush().await?;
+        self.read_reply().await
+    }
+
+    // a command that has to be answered with one of `expected`
+    async fn expect(&mut self, command: &str, expected: &[u16]) -> Result<String, BoxError> {
+        let (code, text) = self.command(command).await?;
+        if expected.contains(&code) {
+            return Ok(text);
+        }
+        let shown = if command.starts_with("PASS ") { "PASS" } else { command };
+        Err(format!("FTP {} failed: {} {}", shown, code, text).into())
+    }
+}
+
+// uploads to `ftp://user@host:port/dir` in passive mode, optionally with
+// explicit FTPS; every running transfer has its own control connection
+pub struct FtpBackend {
+    host: String,
+    port: u16,
+    user: String,
+    password: String,
+    // target directory, empty for the login directory
+    root: String,
+    options: FtpOptions,
+    tls: Option<TlsConnector>,
+    // logged in connections waiting for the next file
+    idle: Mutex<Vec<Control>>,
+    // directories created or found by earlier files
+    dirs: Mutex<HashSet<String>>,
+    // one file at a time reads and writes `options.state_file`
+    state_lock: Mutex<()>,
+}
+
+impl std::fmt::Debug for FtpBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FtpBackend")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.user)
+            .field("root", &self.root)
+            .field("explicit_tls", &self.options.explicit_tls)
+            .finish()
+    }
+}
+
+impl FtpBackend {
+    pub fn new(target: HttpTarget, options: FtpOptions) -> Result<Self, BoxError> {
+        let url = reqwest::Url::parse(&target.url).map_err(|e| format!("Invalid FTP URL {}: {}", target.url, e))?;
+        if url.scheme() != "ftp" {
+            return Err(format!("Invalid FTP URL {}, expected ftp://[user@]host[:port]/dir", target.url).into());
+        }
+        let host = url.host_str().ok_or("The FTP URL has no host")?.to_string();
+
+        let user = match url.username() {
+            "" => options.user.clone().unwrap_or_else(|| "anonymous".to_string()),
+            user => percent_decode(user),
+        };
+        let password = match url.password() {
+            Some(password) => percent_decode(password),
+            None => options.password.clone().unwrap_or_else(|| "anonymous@".to_string()),
+        };
+        // a line break would end the USER or PASS command and start another
+        if user.contains(['\r', '\n']) || password.contains(['\r', '\n']) {
+            return Err(Error::Config("The FTP user and password can't contain line breaks".into()).into());
+        }
+
+        let tls = if options.explicit_tls {
+            let mut config = options.tls.client_config()?;
+            // the configuration is shared with HTTP, FTP negotiates no ALPN
+            config.alpn_protocols.clear();
+            Some(TlsConnector::from(Arc::new(config)))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            host,
+            port: url.port().unwrap_or(DEFAULT_PORT),
+            user,
+            password,
+            root: percent_decode(url.path()).trim_end_matches('/').to_string(),
+            options,
+            tls,
+            idle: Mutex::new(Vec::new()),
+            dirs: Mutex::new(HashSet::new()),
+            state_lock: Mutex::new(()),
+        })
+    }
+
+    async fn tcp(&self, address: (IpAddr, u16)) -> Result<TcpStream, BoxError> {
+        let connect = TcpStream::connect(address);
+        let stream = match self.options.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect)
+                .await
+                .map_err(|_| Error::Timeout(format!("Connecting to {}:{} timed out", address.0, address.1).into()))?,
+            None => connect.await,
+        };
+        stream.map_err(|e| Error::Transport(format!("Failed to connect to {}:{}: {}", address.0, address.1, e).into()).into())
+    }
+
+    // TLS with the same configuration on control and data connections, so
+    // servers that insist on session reuse accept the data connection
+    async fn secure(&self, tls: &TlsConnector, tcp: TcpStream) -> Result<Stream, BoxError> {
+        let server_name = ServerName::try_from(self.host.clone())?;
+        Ok(Stream::Tls(Box::new(tls.connect(server_name, tcp).await?)))
+    }
+
+    async fn connect(&self) -> Result<Control, BoxError> {
+        let tcp = match tokio::net::lookup_host((self.host.as_str(), self.port)).await?.next() {
+            Some(address) => self.tcp((address.ip(), address.port())).await?,
+            None => return Err(format!("Failed to resolve {}", self.host).into()),
+        };
+        let peer = tcp.peer_addr()?.ip();
+
+        let mut control = Control {
+            stream: BufReader::new(Stream::Plain(tcp)),
+            peer,
+        };
+        let (code, text) = control.read_reply().await?;
+        if code != 220 {
+            return Err(format!("FTP server not ready: {} {}", code, text).into());
+        }
+
+        if let Some(tls) = &self.tls {
+            control.expect("AUTH TLS", &[234]).await?;
+            let Stream::Plain(tcp) = control.stream.into_inner() else {
+                return Err("The FTP control connection is already secured".into());
+            };
+            control = Control {
+                stream: BufReader::new(self.secure(tls, tcp).await?),
+                peer,
+            };
+        }
+
+        let (code, text) = control.command(&format!("USER {}", self.user)).await?;
+        match code {
+            230 => {}
+            331 | 332 => {
+                control
+                    .expect(&format!("PASS {}", self.password), &[202, 230])
+                    .await
+                    .map_err(|_| Error::Auth(format!("FTP login as {} failed", self.user)))?;
+            }
+            _ => return Err(Error::Auth(format!("FTP login as {} failed: {} {}", self.user, code, text)).into()),
+        }
+
+        if self.tls.is_some() {
+            control.expect("PBSZ 0", &[200]).await?;
+            control.expect("PROT P", &[200]).await?;
+        }
+        control.expect("TYPE I", &[200]).await?;
+
+        Ok(control)
+    }
+
+    // an idle connection that still answers, or a new one
+    async fn take(&self) -> Result<Control, BoxError> {
+        loop {
+            let idle = self.idle.lock().unwrap().pop();
+            match idle {
+                Some(mut control) => {
+                    if let Ok((200, _)) = control.command("NOOP").await {
+                        return Ok(control);
+                    }
+                }
+                None => return self.connect().await,
+            }
+        }
+    }
+
+    fn release(&self, control: Control) {
+        self.idle.lock().unwrap().push(control);
+    }
+
+    // passive mode, EPSV first and PASV for servers that only know that
+    async fn open_data(&self, control: &mut Control) -> Result<TcpStream, BoxError> {
+        let (code, text) = control.command("EPSV").await?;
+        let port = if code == 229 {
+            // 229 Entering Extended Passive Mode (|||6446|)
+            text.split('|').nth(3).and_then(|port| port.parse().ok())
+        } else {
+            // 227 Entering Passive Mode (h1,h2,h3,h4,p1,p2)
+            let text = control.expect("PASV", &[227]).await?;
+            passive_port(&text)
+        };
+        let port = port.filter(|&port| port != 0).ok_or_else(|| format!("Invalid passive mode reply: {}", text))?;
+
+        self.tcp((control.peer, port)).await
+    }
+
+    async fn store(&self, control: &mut Control, path: &str, offset: u64, mut body: ByteStream) -> Result<(), BoxError> {
+        let data = self.open_data(control).await?;
+
+        let command = if offset > 0 {
+            // servers that refuse REST before STOR can still append
+            match control.command(&format!("REST {}", offset)).await? {
+                (350, _) => format!("STOR {}", path),
+                _ => format!("APPE {}", path),
+            }
+        } else {
+            format!("STOR {}", path)
+        };
+        control.expect(&command, &[125, 150]).await?;
+
+        let mut data = match &self.tls {
+            Some(tls) => self.secure(tls, data).await?,
+            None => Stream::Plain(data),
+        };
+        while let Some(chunk) = body.next().await {
+            data.write_all(&chunk?).await?;
+        }
+        // close_notify and FIN tell the server the file is complete
+        data.shutdown().await?;
+        drop(data);
+
+        let (code, text) = control.read_reply().await?;
+        if code != 226 && code != 250 {
+            return Err(format!("FTP {} failed: {} {}", command, code, text).into());
+        }
+        Ok(())
+    }
+
+    fn remote_dirs(&self, file: &FileInfo) -> Vec<String> {
+        let mut dirs = Vec::new();
+        let mut path = self.root.clone();
+        for component in file.relative_path.parent().into_iter().flat_map(|parent| parent.components()) {
+            if let Component::Normal(name) = component {
+                if !path.is_empty() {
+                    path.push('/');
+                }
+                path.push_str(&name.to_string_lossy());
+                dirs.push(path.clone());
+            }
+        }
+        dirs
+    }
+
+    fn remote_path(&self, file: &FileInfo) -> String {
+        match self.remote_dirs(file).pop() {
+            Some(dir) => format!("{}/{}", dir, file.name),
+            None if self.root.is_empty() => file.name.clone(),
+            None => format!("{}/{}", self.root, file.name),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("ftp://{}:{}/{}", self.host, self.port, path.trim_start_matches('/'))
+    }
+
+    // the local file behind a partial remote one, a changed file starts over
+    fn fingerprint(file: &FileInfo) -> Option<String> {
+        let modified = std::fs::metadata(&file.path).ok()?.modified().ok()?;
+        let modified = modified.duration_since(std::time::UNIX_EPOCH).ok()?.as_nanos();
+        Some(format!("{} {} {}", file.path.display(), file.size, modified))
+    }
+
+    // whether an earlier run left this file partly uploaded to `path`
+    fn interrupted(&self, file: &FileInfo, path: &str) -> bool {
+        let (Some(state_file), Some(fingerprint)) = (&self.options.state_file, Self::fingerprint(file)) else {
+            return false;
+        };
+        let _lock = self.state_lock.lock().unwrap();
+        read_state(state_file).get(&self.url(path)).and_then(Value::as_str) == Some(fingerprint.as_str())
+    }
+
+    // records or forgets a partial upload, losing the state only costs a resume
+    fn record(&self, file: &FileInfo, path: &str, interrupted: bool) {
+        let Some(state_file) = &self.options.state_file else {
+            return;
+        };
+        let _lock = self.state_lock.lock().unwrap();
+        let mut state = read_state(state_file);
+        match Self::fingerprint(file).filter(|_| interrupted) {
+            Some(fingerprint) => state.insert(self.url(path), Value::from(fingerprint)),
+            // nothing to write for files that were never interrupted
+            None => match state.remove(&self.url(path)) {
+                Some(removed) => Some(removed),
+                None => return,
+            },
+        };
+
+        let write = || -> std::io::Result<()> {
+            if let Some(dir) = state_file.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(state_file, Value::Object(state).to_string())
+        };
+        if let Err(e) = write() {
+            file.reporter.warn(&format!("failed to update {}: {}", state_file.display(), e));
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for FtpBackend {
+    async fn init(&self, file: &FileInfo) -> Result<Session, BoxError> {
+        let path = self.remote_path(file);
+        // a line break would end the command and start another
+        if path.contains(['\r', '\n']) {
+            return Err(format!("Invalid FTP path {:?}, names can't contain line breaks", path).into());
+        }
+        let mut control = self.take().await?;
+
+        for dir in self.remote_dirs(file) {
+            if self.dirs.lock().unwrap().contains(&dir) {
+                continue;
+            }
+            // 550 when it exists already, a real failure shows up at STOR
+            control.command(&format!("MKD {}", dir)).await?;
+            self.dirs.lock().unwrap().insert(dir);
+        }
+
+        // only a file this run's file was interrupted in, anything else of the same name is replaced
+        let mut offset = 0;
+        if file.resumable && self.interrupted(file, &path) {
+            if let (213, size) = control.command(&format!("SIZE {}", path)).await? {
+                let size: u64 = size.trim().parse().unwrap_or_default();
+                if size > 0 && size < file.size {
+                    offset = size;
+                }
+            }
+        }
+        self.release(control);
+
+        Ok(Session {
+            location: Some(path),
+            offset,
+            ..Default::default()
+        })
+    }
+
+    async fn put_part(&self, _file: &FileInfo, session: &Session, part: Part) -> Result<PartReceipt, BoxError> {
+        let path = session.location.as_deref().ok_or("No FTP upload in progress")?;
+        let body = match part.body {
+            PartBody::Stream(stream) => stream,
+            PartBody::Bytes(data) => Box::pin(futures::stream::once(async move { Ok(data) })),
+        };
+
+        // a connection with a failed transfer is in an unknown state and dropped
+        let mut control = self.take().await?;
+        self.store(&mut control, path, part.offset, body).await?;
+        self.release(control);
+
+        Ok(PartReceipt {
+            index: part.index,
+            ..Default::default()
+        })
+    }
+
+    async fn complete(
+        &self,
+        file: &FileInfo,
+        session: &Session,
+        _receipts: Vec<PartReceipt>,
+    ) -> Result<Option<String>, BoxError> {
+        let path = session.location.as_deref().unwrap_or_default();
+        // also after a full upload, a record left by an older run would resume the next one wrongly
+        self.record(file, path, false);
+        Ok(Some(self.url(path)))
+    }
+
+    async fn abort(&self, file: &FileInfo, session: &Session) -> Result<(), BoxError> {
+        let Some(path) = &session.location else {
+            return Ok(());
+        };
+
+        if self.options.state_file.is_some() && file.resumable {
+            self.record(file, path, true);
+            file.reporter.report(Notice::Resumable { location: None });
+            return Ok(());
+        }
+
+        // a partial file that can't be continued is only in the way
+        let mut control = self.take().await?;
+        control.command(&format!("DELE {}", path)).await?;
+        self.release(control);
+        Ok(())
+    }
+}
+
+fn read_state(path: &std::path::Path) -> Map<String, Value> {
+    std::fs::read(path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+// the port of a 227 reply, `(h1,h2,h3,h4,p1,p2)`; p1 and p2 are its bytes
+fn passive_port(text: &str) -> Option<u16> {
+    let numbers: Vec<&str> = text
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|number| !number.is_empty())
+        .collect();
+    match numbers.as_slice() {
+        [.., p1, p2] if numbers.len() >= 6 => {
+            let (p1, p2) = (p1.parse::<u8>().ok()?, p2.parse::<u8>().ok()?);
+            Some(u16::from(p1) * 256 + u16::from(p2))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passive_ports() {
+        assert_eq!(passive_port("227 Entering Passive Mode (192,168,1,2,19,136)."), Some(5000));
+        assert_eq!(passive_port("227 Entering Passive Mode (127,0,0,1,255,255)"), Some(65535));
+        assert_eq!(passive_port("227 =127,0,0,1,0,21"), Some(21));
+        // bytes out of range, e.g. from a broken server, are no port
+        assert_eq!(passive_port("227 Entering Passive Mode (127,0,0,1,256,0)"), None);
+        assert_eq!(passive_port("227 Entering Passive Mode (127,0,0,1,65535,65535)"), None);
+        assert_eq!(passive_port("227 Entering Passive Mode (127,0,0,1,99999999999,1)"), None);
+        assert_eq!(passive_port("227 Entering Passive Mode (19,136)"), None);
+        assert_eq!(passive_port("227 Entering Passive Mode"), None);
+    }
+}
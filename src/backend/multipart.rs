@@ -0,0 +1,34 @@
+use crate::backend::{check_status, part_body, Backend, BoxError, FileInfo, HttpTarget, Part, PartReceipt, Session};
+use async_trait::async_trait;
+use reqwest::multipart;
+
+// the whole file as the `file` field of a multipart/form-data POST
+#[derive(Debug)]
+pub struct MultipartBackend {
+    target: HttpTarget,
+}
+
+impl MultipartBackend {
+    pub fn new(target: HttpTarget) -> Self {
+        Self { target }
+    }
+}
+
+#[async_trait]
+impl Backend for MultipartBackend {
+    async fn put_part(&self, file: &FileInfo, _session: &Session, part: Part) -> Result<PartReceipt, BoxError> {
+        let part_form = multipart::Part::stream_with_length(part_body(part.body), part.len)
+            .file_name(file.name.clone())
+            .mime_str("application/octet-stream")?;
+
+        let form = multipart::Form::new().part("file", part_form);
+
+        let response = check_status(self.target.post().multipart(form).send().await?).await?;
+
+        Ok(PartReceipt {
+            index: part.index,
+            response: Some(response.text().await?),
+            ..Default::default()
+        })
+    }
+}
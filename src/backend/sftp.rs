@@ -0,0 +1,393 @@
+use crate::backend::{percent_decode, Backend, BoxError, FileInfo, HttpTarget, Part, PartBody, PartReceipt, Session};
+use crate::error::Error;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use ssh2::{CheckResult, KnownHostFileKind, OpenFlags, OpenType, RenameFlags, Sftp};
+use std::collections::HashSet;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DEFAULT_PORT: u16 = 22;
+// chunks of a file buffered between the reader and the blocking SFTP writer
+const WRITE_QUEUE: usize = 4;
+
+#[derive(Debug, Clone, Default)]
+pub struct SftpOptions {
+    // used when the URL has no user, defaults to $USER
+    pub user: Option<String>,
+    pub password: Option<String>,
+    // private key file, the agent and ~/.ssh/id_* are tried without one
+    pub key: Option<PathBuf>,
+    pub key_passphrase: Option<String>,
+    // defaults to ~/.ssh/known_hosts
+    pub known_hosts: Option<PathBuf>,
+    pub connect_timeout: Option<Duration>,
+}
+
+// where to connect and how to log in, shared with the blocking threads
+#[derive(Debug)]
+struct Endpoint {
+    host: String,
+    port: u16,
+    user: String,
+    options: SftpOptions,
+}
+
+// uploads over SFTP to `sftp://user@host:port/dir`; one SSH connection is
+// shared by all files, each of them is written through its own SFTP channel
+pub struct SftpBackend {
+    endpoint: Arc<Endpoint>,
+    root: PathBuf,
+    // None until the first file and after the connection was lost
+    session: tokio::sync::Mutex<Option<Arc<ssh2::Session>>>,
+    // directories created or found by earlier files
+    dirs: Mutex<HashSet<PathBuf>>,
+}
+
+impl std::fmt::Debug for SftpBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SftpBackend")
+            .field("endpoint", &self.endpoint)
+            .field("root", &self.root)
+            .finish()
+    }
+}
+
+impl SftpBackend {
+    pub fn new(target: HttpTarget, options: SftpOptions) -> Result<Self, BoxError> {
+        let url = reqwest::Url::parse(&target.url).map_err(|e| format!("Invalid SFTP URL {}: {}", target.url, e))?;
+        if url.scheme() != "sftp" {
+            return Err(format!("Invalid SFTP URL {}, expected sftp://[user@]host[:port]/dir", target.url).into());
+        }
+        let host = url.host_str().ok_or("The SFTP URL has no host")?.to_string();
+
+        let user = match url.username() {
+            "" => options
+                .user
+                .clone()
+                .or_else(|| std::env::var("USER").ok())
+                .ok_or("No SSH user, add one to the URL or use --user")?,
+            user => percent_decode(user),
+        };
+
+        // like curl, /~/ starts at the home directory
+        let path = percent_decode(url.path());
+        let root = match path.strip_prefix("/~") {
+            Some(home) => PathBuf::from(".").join(home.trim_start_matches('/')),
+            None if path.is_empty() => PathBuf::from("."),
+            None => PathBuf::from(path),
+        };
+
+        Ok(Self {
+            endpoint: Arc::new(Endpoint {
+                host,
+                port: url.port().unwrap_or(DEFAULT_PORT),
+                user,
+                options,
+            }),
+            root,
+            session: tokio::sync::Mutex::new(None),
+            dirs: Mutex::new(HashSet::new()),
+        })
+    }
+
+    // connects on first use, so a bad host fails the first file instead of startup; true for a new connection
+    async fn session(&self) -> Result<(Arc<ssh2::Session>, bool), BoxError> {
+        let mut session = self.session.lock().await;
+        if let Some(session) = &*session {
+            return Ok((session.clone(), false));
+        }
+        let endpoint = self.endpoint.clone();
+        let connected = Arc::new(tokio::task::spawn_blocking(move || endpoint.connect()).await??);
+        *session = Some(connected.clone());
+        Ok((connected, true))
+    }
+
+    // forgets a lost connection, unless another file connected again already
+    async fn disconnect(&self, session: &Arc<ssh2::Session>) {
+        let mut current = self.session.lock().await;
+        if current.as_ref().is_some_and(|current| Arc::ptr_eq(current, session)) {
+            *current = None;
+        }
+    }
+
+    // a new channel, on a new connection when the shared one was lost, e.g. closed by the server while idle
+    async fn sftp(&self) -> Result<(Arc<ssh2::Session>, Sftp), BoxError> {
+        loop {
+            let (session, connected) = self.session().await?;
+            let channel = session.clone();
+            match tokio::task::spawn_blocking(move || channel.sftp()).await? {
+                Ok(sftp) => return Ok((session, sftp)),
+                Err(e) if connected => return Err(e.into()),
+                Err(_) => self.disconnect(&session).await,
+            }
+        }
+    }
+
+    // runs SFTP calls off the async threads, every call gets its own channel
+    async fn with_sftp<T, F>(&self, f: F) -> Result<T, BoxError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Sftp) -> Result<T, BoxError> + Send + 'static,
+    {
+        let (session, sftp) = self.sftp().await?;
+        let channel = session.clone();
+        let (result, lost) = tokio::task::spawn_blocking(move || {
+            let result = f(&sftp);
+            // errors of the file leave the connection usable, a lost one can't open another channel
+            let lost = result.is_err() && channel.sftp().is_err();
+            (result, lost)
+        })
+        .await?;
+        if lost {
+            self.disconnect(&session).await;
+        }
+        result
+    }
+
+    fn remote_dir(&self, file: &FileInfo) -> PathBuf {
+        let mut dir = self.root.clone();
+        for component in file.relative_path.parent().into_iter().flat_map(Path::components) {
+            if let Component::Normal(name) = component {
+                dir.push(name);
+            }
+        }
+        dir
+    }
+}
+
+impl Endpoint {
+    fn connect(&self) -> Result<ssh2::Session, BoxError> {
+        let address = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("Failed to resolve {}", self.host))?;
+        let tcp = match self.options.connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+            None => TcpStream::connect(address),
+        }
+        .map_err(|e| Error::Transport(format!("Failed to connect to {}:{}: {}", self.host, self.port, e).into()))?;
+
+        let mut session = ssh2::Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+
+        self.verify_host_key(&session)?;
+        self.authenticate(&session)?;
+
+        Ok(session)
+    }
+
+    fn verify_host_key(&self, session: &ssh2::Session) -> Result<(), BoxError> {
+        let path = self
+            .options
+            .known_hosts
+            .clone()
+            .or_else(|| dirs::home_dir().map(|home| home.join(".ssh").join("known_hosts")))
+            .ok_or("No known_hosts file, use --known-hosts")?;
+
+        let (key, _) = session.host_key().ok_or("The server sent no host key")?;
+        let mut known_hosts = session.known_hosts()?;
+        known_hosts
+            .read_file(&path, KnownHostFileKind::OpenSSH)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => Err(Error::Auth(format!(
+                "{} is not in {}, connect once with ssh to verify its host key",
+                self.host,
+                path.display()
+            ))
+            .into()),
+            CheckResult::Mismatch => Err(Error::Auth(format!(
+                "The host key of {} does not match {}, someone may be impersonating the server",
+                self.host,
+                path.display()
+            ))
+            .into()),
+            CheckResult::Failure => Err(format!("Failed to check the host key of {}", self.host).into()),
+        }
+    }
+
+    fn authenticate(&self, session: &ssh2::Session) -> Result<(), BoxError> {
+        let user = self.user.as_str();
+        let passphrase = self.options.key_passphrase.as_deref();
+
+        match &self.options.key {
+            Some(key) => session
+                .userauth_pubkey_file(user, None, key, passphrase)
+                .map_err(|e| Error::Auth(format!("Key authentication with {} failed: {}", key.display(), e)))?,
+            None => {
+                // the agent first, then the usual key files
+                let _ = session.userauth_agent(user);
+                let ssh_dir = dirs::home_dir().map(|home| home.join(".ssh"));
+                for name in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+                    if session.authenticated() {
+                        break;
+                    }
+                    if let Some(key) = ssh_dir.as_ref().map(|dir| dir.join(name)).filter(|key| key.exists()) {
+                        let _ = session.userauth_pubkey_file(user, None, &key, passphrase);
+                    }
+                }
+                if let (false, Some(password)) = (session.authenticated(), &self.options.password) {
+                    let _ = session.userauth_password(user, password);
+                }
+            }
+        }
+
+        if !session.authenticated() {
+            return Err(Error::Auth(format!("SSH authentication failed for {}@{}", user, self.host)).into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Backend for SftpBackend {
+    async fn init(&self, file: &FileInfo) -> Result<Session, BoxError> {
+        let dir = self.remote_dir(file);
+        if dir != self.root && !self.dirs.lock().unwrap().contains(&dir) {
+            let mut path = self.root.clone();
+            let below_root = dir.strip_prefix(&self.root)?.to_path_buf();
+            self.with_sftp(move |sftp| {
+                for component in below_root.components() {
+                    path.push(component);
+                    // fails when the directory exists, which stat confirms
+                    if sftp.mkdir(&path, 0o755).is_err() && !sftp.stat(&path).is_ok_and(|stat| stat.is_dir()) {
+                        return Err(format!("Failed to create directory {}", path.display()).into());
+                    }
+                }
+                Ok(())
+            })
+            .await?;
+            self.dirs.lock().unwrap().insert(dir.clone());
+        }
+
+        // written under a temporary name, so an interrupted upload never
+        // replaces a complete file
+        let path = dir.join(&file.name);
+        let partial = dir.join(format!(".{}.part", file.name));
+        Ok(Session {
+            id: Some(partial.to_string_lossy().to_string()),
+            location: Some(path.to_string_lossy().to_string()),
+            ..Default::default()
+        })
+    }
+
+    async fn put_part(&self, _file: &FileInfo, session: &Session, part: Part) -> Result<PartReceipt, BoxError> {
+        let (partial, path) = session_paths(session)?;
+
+        // the file is read on this side and written by a blocking thread; None
+        // ends the file, a channel closed without it means the reader failed or
+        // was dropped, e.g. on Ctrl-C, while this thread keeps running
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Option<Bytes>>(WRITE_QUEUE);
+        let writer = self.with_sftp(move |sftp| {
+            let mut remote = sftp.open_mode(
+                &partial,
+                OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                0o644,
+                OpenType::File,
+            )?;
+            let mut write = || {
+                while let Some(data) = rx.blocking_recv() {
+                    match data {
+                        Some(data) => remote.write_all(&data)?,
+                        None => return Ok(true),
+                    }
+                }
+                Ok::<_, BoxError>(false)
+            };
+            let complete = write().and_then(|complete| {
+                remote.close()?;
+                Ok(complete)
+            });
+            drop(remote);
+            match complete {
+                Ok(true) => {}
+                // the partial file never replaces the existing one
+                Ok(false) => {
+                    let _ = sftp.unlink(&partial);
+                    return Err("The upload stopped before the end of the file".into());
+                }
+                Err(e) => {
+                    let _ = sftp.unlink(&partial);
+                    return Err(e);
+                }
+            }
+
+            let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+            if sftp.rename(&partial, &path, Some(flags)).is_err() {
+                // servers without posix-rename refuse to replace existing files
+                let _ = sftp.unlink(&path);
+                sftp.rename(&partial, &path, None)?;
+            }
+            Ok(())
+        });
+
+        let mut body = match part.body {
+            PartBody::Stream(stream) => stream,
+            PartBody::Bytes(data) => Box::pin(futures::stream::once(async move { Ok(data) })),
+        };
+        let reader = async move {
+            while let Some(data) = body.next().await {
+                // a closed channel means the writer failed, its error is reported
+                if tx.send(Some(data?)).await.is_err() {
+                    return Ok(());
+                }
+            }
+            let _ = tx.send(None).await;
+            Ok::<_, BoxError>(())
+        };
+
+        // the reader's error first, the writer only saw the file end early
+        let (read, written) = tokio::join!(reader, writer);
+        read?;
+        written?;
+
+        Ok(PartReceipt {
+            index: part.index,
+            ..Default::default()
+        })
+    }
+
+    async fn complete(
+        &self,
+        _file: &FileInfo,
+        session: &Session,
+        _receipts: Vec<PartReceipt>,
+    ) -> Result<Option<String>, BoxError> {
+        let (_, path) = session_paths(session)?;
+        Ok(Some(format!(
+            "sftp://{}@{}:{}/{}",
+            self.endpoint.user,
+            self.endpoint.host,
+            self.endpoint.port,
+            path.display().to_string().trim_start_matches('/')
+        )))
+    }
+
+    async fn abort(&self, _file: &FileInfo, session: &Session) -> Result<(), BoxError> {
+        let (partial, _) = session_paths(session)?;
+        // nothing was written without a connection
+        if self.session.lock().await.is_none() {
+            return Ok(());
+        }
+        self.with_sftp(move |sftp| {
+            let _ = sftp.unlink(&partial);
+            Ok(())
+        })
+        .await
+    }
+}
+
+fn session_paths(session: &Session) -> Result<(PathBuf, PathBuf), BoxError> {
+    match (&session.id, &session.location) {
+        (Some(partial), Some(path)) => Ok((PathBuf::from(partial), PathBuf::from(path))),
+        _ => Err("No SFTP upload in progress".into()),
+    }
+}
@@ -0,0 +1,352 @@
+use crate::backend::{Backend, BoxError, FileInfo, HttpTarget, Notice, Part, PartBody, PartReceipt, Session};
+use crate::error::Error;
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine};
+use bytes::Bytes;
+use reqwest::{Method, RequestBuilder, Response, StatusCode, Url};
+use serde_json::{Map, Value};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::{Mutex, OnceCell};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+const TUS_VERSION: &str = "1.0.0";
+// PATCH attempts per chunk before the upload is given up
+const MAX_ATTEMPTS: u32 = 5;
+const CHECKSUM_MISMATCH: u16 = 460;
+
+#[derive(Debug, Clone, Default)]
+pub struct TusOptions {
+    // where upload URLs are kept between runs, no resuming across runs when unset
+    pub state_file: Option<PathBuf>,
+}
+
+impl TusOptions {
+    pub fn default_state_file() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("dup-cli").join("tus.json"))
+    }
+}
+
+// what the server announced with OPTIONS
+#[derive(Debug, Default)]
+struct Capabilities {
+    termination: bool,
+    // `sha256` or `sha1`, the strongest one both sides support
+    checksum: Option<&'static str>,
+}
+
+// resumable uploads following https://tus.io/protocols/resumable-upload:
+// POST creates the upload, PATCH appends chunks at `Upload-Offset`, and HEAD
+// tells where to continue after an interruption, in this run or a later one
+#[derive(Debug)]
+pub struct TusBackend {
+    target: HttpTarget,
+    chunk_size: usize,
+    options: TusOptions,
+    capabilities: OnceCell<Capabilities>,
+    // serializes read-modify-write of the state file between parallel files
+    state_lock: Mutex<()>,
+}
+
+impl TusBackend {
+    pub fn new(target: HttpTarget, chunk_size: usize, options: TusOptions) -> Self {
+        Self {
+            target,
+            chunk_size,
+            options,
+            capabilities: OnceCell::new(),
+            state_lock: Mutex::new(()),
+        }
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.target
+            .client
+            .request(method, url)
+            .headers(self.target.headers.clone())
+            .header("Tus-Resumable", TUS_VERSION)
+    }
+
+    async fn capabilities(&self) -> &Capabilities {
+        self.capabilities
+            .get_or_init(|| async {
+                // servers without OPTIONS still support the core protocol
+                let Ok(response) = self.request(Method::OPTIONS, &self.target.url).send().await else {
+                    return Capabilities::default();
+                };
+                let header = |name: &str| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or_default()
+                        .split(',')
+                        .map(|value| value.trim().to_string())
+                        .collect::<Vec<_>>()
+                };
+                let extensions = header("Tus-Extension");
+                let algorithms = header("Tus-Checksum-Algorithm");
+
+                let checksum = if !extensions.iter().any(|extension| extension == "checksum") {
+                    None
+                } else if algorithms.iter().any(|algorithm| algorithm == "sha256") {
+                    Some("sha256")
+                } else if algorithms.iter().any(|algorithm| algorithm == "sha1") {
+                    Some("sha1")
+                } else {
+                    None
+                };
+
+                Capabilities {
+                    termination: extensions.iter().any(|extension| extension == "termination"),
+                    checksum,
+                }
+            })
+            .await
+    }
+
+    // the current offset of an upload, None when the server no longer has it
+    async fn offset(&self, upload_url: &str, length: u64) -> Result<Option<u64>, BoxError> {
+        let response = self.request(Method::HEAD, upload_url).send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE | StatusCode::FORBIDDEN => return Ok(None),
+            status if !status.is_success() => {
+                return Err(Error::from_status(status, response.text().await.unwrap_or_default()).into());
+            }
+            _ => {}
+        }
+
+        // an upload of a different length belongs to another version of the file
+        if header_u64(&response, "Upload-Length").is_some_and(|upload_length| upload_length != length) {
+            return Ok(None);
+        }
+        Ok(Some(
+            header_u64(&response, "Upload-Offset").ok_or("HEAD returned no Upload-Offset")?,
+        ))
+    }
+
+    async fn create(&self, file: &FileInfo) -> Result<String, BoxError> {
+        let metadata = format!("filename {}", general_purpose::STANDARD.encode(&file.name));
+        let response = self
+            .request(Method::POST, &self.target.url)
+            .header("Upload-Length", file.size)
+            .header("Upload-Metadata", metadata)
+            .send()
+            .await?;
+        if response.status() != StatusCode::CREATED {
+            let status = response.status();
+            return Err(Error::from_status(status, response.text().await.unwrap_or_default()).into());
+        }
+
+        let location = response
+            .headers()
+            .get("Location")
+            .and_then(|location| location.to_str().ok())
+            .ok_or("The server returned no upload URL")?;
+        // Location may be relative to the creation URL
+        Ok(Url::parse(&self.target.url)?.join(location)?.to_string())
+    }
+
+    async fn patch(&self, upload_url: &str, offset: u64, data: Bytes) -> Result<Response, BoxError> {
+        let mut request = self
+            .request(Method::PATCH, upload_url)
+            .header("Content-Type", "application/offset+octet-stream")
+            .header("Upload-Offset", offset);
+        match self.capabilities().await.checksum {
+            Some("sha256") => {
+                let digest = general_purpose::STANDARD.encode(Sha256::digest(&data));
+                request = request.header("Upload-Checksum", format!("sha256 {}", digest));
+            }
+            Some(_) => {
+                let digest = general_purpose::STANDARD.encode(Sha1::digest(&data));
+                request = request.header("Upload-Checksum", format!("sha1 {}", digest));
+            }
+            None => {}
+        }
+        Ok(request.body(data).send().await?)
+    }
+
+    // identifies a file across runs, a changed file starts a new upload
+    fn fingerprint(&self, file: &FileInfo) -> Option<String> {
+        let modified = std::fs::metadata(&file.path).ok()?.modified().ok()?;
+        let modified = modified.duration_since(std::time::UNIX_EPOCH).ok()?.as_nanos();
+        Some(format!(
+            "{} {} {} {}",
+            self.target.url,
+            file.path.display(),
+            file.size,
+            modified
+        ))
+    }
+
+    async fn stored_url(&self, file: &FileInfo) -> Option<String> {
+        let (Some(path), Some(fingerprint)) = (&self.options.state_file, self.fingerprint(file)) else {
+            return None;
+        };
+        let _lock = self.state_lock.lock().await;
+        read_state(path).get(&fingerprint)?.as_str().map(str::to_string)
+    }
+
+    // keeps or forgets the upload URL of a file, losing the state only costs a resume
+    async fn store_url(&self, file: &FileInfo, upload_url: Option<&str>) {
+        let (Some(path), Some(fingerprint)) = (&self.options.state_file, self.fingerprint(file)) else {
+            return;
+        };
+        let _lock = self.state_lock.lock().await;
+        let mut state = read_state(path);
+        match upload_url {
+            Some(upload_url) => state.insert(fingerprint, Value::from(upload_url)),
+            None => state.remove(&fingerprint),
+        };
+
+        let write = || -> std::io::Result<()> {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(path, Value::Object(state).to_string())
+        };
+        if let Err(e) = write() {
+            file.reporter.warn(&format!("failed to update {}: {}", path.display(), e));
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for TusBackend {
+    fn chunk_size(&self, _file_size: u64, preferred: Option<usize>) -> Option<usize> {
+        Some(preferred.unwrap_or(self.chunk_size))
+    }
+
+    // PATCH requests of one upload have to arrive in order
+    fn max_concurrency(&self) -> usize {
+        1
+    }
+
+    async fn init(&self, file: &FileInfo) -> Result<Session, BoxError> {
+        self.capabilities().await;
+
+        if file.resumable {
+            if let Some(upload_url) = self.stored_url(file).await {
+                if let Ok(Some(offset)) = self.offset(&upload_url, file.size).await {
+                    return Ok(Session {
+                        id: Some(upload_url),
+                        offset,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        let upload_url = self.create(file).await?;
+        if file.resumable {
+            self.store_url(file, Some(&upload_url)).await;
+        }
+
+        Ok(Session {
+            id: Some(upload_url),
+            ..Default::default()
+        })
+    }
+
+    async fn put_part(&self, file: &FileInfo, session: &Session, part: Part) -> Result<PartReceipt, BoxError> {
+        let PartBody::Bytes(data) = part.body else {
+            return Err("tus chunks need the chunk in memory".into());
+        };
+        let upload_url = session.id.as_deref().ok_or("No tus upload in progress")?;
+        let end = part.offset + part.len;
+
+        let mut offset = part.offset.max(session.offset);
+        let mut attempt = 0;
+        while offset < end {
+            attempt += 1;
+            let body = data.slice((offset - part.offset) as usize..);
+
+            let error: Error = match self.patch(upload_url, offset, body).await {
+                Ok(response) if response.status() == StatusCode::NO_CONTENT => {
+                    offset = header_u64(&response, "Upload-Offset").ok_or("PATCH returned no Upload-Offset")?;
+                    attempt = 0;
+                    continue;
+                }
+                Ok(response) if response.status().as_u16() == CHECKSUM_MISMATCH => {
+                    Error::ChecksumMismatch("The server rejected the chunk checksum".to_string())
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    Error::from_status(status, response.text().await.unwrap_or_default())
+                }
+                Err(e) => e.into(),
+            };
+
+            // a conflict is an offset the server disagrees with, fixed by asking
+            // for it below; other refused chunks would be refused again
+            let conflict = matches!(error, Error::Http { status: StatusCode::CONFLICT, .. });
+            if attempt >= MAX_ATTEMPTS || !(error.is_retryable() || conflict) {
+                return Err(error.into());
+            }
+            file.reporter.warn(&format!("chunk {} of {} failed ({}), resuming", part.index + 1, file.name, error));
+            tokio::time::sleep(Duration::from_millis(500 * 2u64.pow(attempt - 1))).await;
+
+            // the server may have stored part of the failed request, when it
+            // can't tell, the next PATCH is retried at the same offset
+            match self.offset(upload_url, file.size).await {
+                Ok(Some(server_offset)) => offset = server_offset,
+                Ok(None) => return Err("The server no longer has the upload".into()),
+                Err(_) => continue,
+            }
+            if offset < part.offset {
+                return Err(format!("The server lost data before byte {}", part.offset).into());
+            }
+        }
+
+        Ok(PartReceipt {
+            index: part.index,
+            ..Default::default()
+        })
+    }
+
+    async fn complete(
+        &self,
+        file: &FileInfo,
+        session: &Session,
+        _receipts: Vec<PartReceipt>,
+    ) -> Result<Option<String>, BoxError> {
+        self.store_url(file, None).await;
+        Ok(session.id.clone())
+    }
+
+    async fn abort(&self, file: &FileInfo, session: &Session) -> Result<(), BoxError> {
+        let Some(upload_url) = &session.id else {
+            return Ok(());
+        };
+
+        // a stored upload is continued by the next run instead
+        if file.resumable && self.options.state_file.is_some() {
+            file.reporter.report(Notice::Resumable {
+                location: Some(upload_url),
+            });
+            return Ok(());
+        }
+
+        if self.capabilities().await.termination {
+            let response = self.request(Method::DELETE, upload_url).send().await?;
+            let status = response.status();
+            if !status.is_success() && status != StatusCode::NOT_FOUND {
+                return Err(Error::from_status(status, response.text().await.unwrap_or_default()).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn header_u64(response: &Response, name: &str) -> Option<u64> {
+    response.headers().get(name)?.to_str().ok()?.parse().ok()
+}
+
+fn read_state(path: &Path) -> Map<String, Value> {
+    std::fs::read(path)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
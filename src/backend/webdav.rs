@@ -0,0 +1,232 @@
+use crate::backend::{part_body, Backend, BoxError, FileInfo, HttpTarget, Part, PartBody, PartReceipt, Session};
+use crate::error::Error;
+use async_trait::async_trait;
+use digest_auth::{AuthContext, HttpMethod, Qop, WwwAuthenticateHeader};
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, IF_NONE_MATCH, WWW_AUTHENTICATE};
+use reqwest::{Method, RequestBuilder, Response, StatusCode, Url};
+use std::collections::HashSet;
+use std::path::Component;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Default)]
+pub struct WebDavOptions {
+    pub user: Option<String>,
+    pub password: Option<String>,
+    // answer digest challenges instead of sending basic credentials
+    pub digest: bool,
+    // refuse to replace files that already exist on the server
+    pub no_overwrite: bool,
+}
+
+// PUT of every file below the --host collection, with the directory tree of
+// the uploaded folders recreated through MKCOL, e.g. for Nextcloud or Apache
+#[derive(Debug)]
+pub struct WebDavBackend {
+    target: HttpTarget,
+    options: WebDavOptions,
+    // collections created or found by earlier files, saves a MKCOL per level and file
+    collections: Mutex<HashSet<String>>,
+    // the server's digest challenge, reused with an increasing nonce count
+    challenge: tokio::sync::Mutex<Option<WwwAuthenticateHeader>>,
+}
+
+impl WebDavBackend {
+    pub fn new(target: HttpTarget, options: WebDavOptions) -> Result<Self, BoxError> {
+        Url::parse(&target.url)
+            .ok()
+            .filter(|url| !url.cannot_be_a_base())
+            .ok_or_else(|| format!("Invalid WebDAV URL {}", target.url))?;
+        if options.digest && options.user.is_none() {
+            return Err("--digest needs credentials from --user".into());
+        }
+
+        Ok(Self {
+            target,
+            options,
+            collections: Mutex::new(HashSet::new()),
+            challenge: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    fn url(&self, segments: &[String], collection: bool) -> Result<Url, BoxError> {
+        let mut url = Url::parse(&self.target.url)?;
+        {
+            let mut path = url.path_segments_mut().map_err(|_| "Invalid WebDAV URL")?;
+            path.pop_if_empty().extend(segments);
+            if collection {
+                path.push("");
+            }
+        }
+        Ok(url)
+    }
+
+    async fn request(&self, method: Method, url: &Url) -> Result<RequestBuilder, BoxError> {
+        let mut request = self
+            .target
+            .client
+            .request(method.clone(), url.clone())
+            .headers(self.target.headers.clone());
+
+        let Some(user) = &self.options.user else {
+            return Ok(request);
+        };
+        let password = self.options.password.as_deref().unwrap_or_default();
+
+        if !self.options.digest {
+            return Ok(request.basic_auth(user, Some(password)));
+        }
+
+        let mut challenge = self.challenge.lock().await;
+        if challenge.is_none() {
+            *challenge = Some(self.fetch_challenge(url).await?);
+        }
+        let uri = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let context = AuthContext::new_with_method(
+            user.as_str(),
+            password,
+            uri,
+            None::<&[u8]>,
+            HttpMethod::from(method.as_str()),
+        );
+        let authorization = challenge
+            .as_mut()
+            .ok_or("No digest challenge")?
+            .respond(&context)
+            .map_err(|e| format!("Digest authentication failed: {}", e))?;
+        request = request.header("Authorization", authorization.to_header_string());
+
+        Ok(request)
+    }
+
+    async fn fetch_challenge(&self, url: &Url) -> Result<WwwAuthenticateHeader, BoxError> {
+        let response = self.target.client.request(Method::OPTIONS, url.clone()).send().await?;
+        parse_challenge(response.headers())
+    }
+
+    // a rejected digest is replaced by the challenge of the 401, e.g. after the nonce expired, or fetched again
+    // for the next request when the 401 has none
+    async fn check_auth(&self, response: &Response) {
+        if response.status() == StatusCode::UNAUTHORIZED && self.options.digest {
+            *self.challenge.lock().await = parse_challenge(response.headers()).ok();
+        }
+    }
+
+    async fn put(&self, url: &Url, len: u64, body: PartBody) -> Result<Response, BoxError> {
+        let mut request = self
+            .request(Method::PUT, url)
+            .await?
+            // streamed bodies would otherwise go out with chunked encoding
+            .header(CONTENT_LENGTH, len)
+            .body(part_body(body));
+        if self.options.no_overwrite {
+            request = request.header(IF_NONE_MATCH, "*");
+        }
+        Ok(request.send().await?)
+    }
+
+    async fn mkcol(&self, segments: &[String]) -> Result<(), BoxError> {
+        let url = self.url(segments, true)?;
+        if self.collections.lock().unwrap().contains(url.as_str()) {
+            return Ok(());
+        }
+
+        let mut attempt = 0;
+        let response = loop {
+            attempt += 1;
+            let response = self.request(Method::from_bytes(b"MKCOL")?, &url).await?.send().await?;
+            self.check_auth(&response).await;
+            // one more try with a fresh nonce
+            if response.status() == StatusCode::UNAUTHORIZED && self.options.digest && attempt < 2 {
+                continue;
+            }
+            break response;
+        };
+
+        // 405: the collection exists already
+        let status = response.status();
+        if !status.is_success() && status != StatusCode::METHOD_NOT_ALLOWED {
+            return Err(Error::from_status(status, response.text().await.unwrap_or_default()).into());
+        }
+        self.collections.lock().unwrap().insert(url.to_string());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Backend for WebDavBackend {
+    async fn init(&self, file: &FileInfo) -> Result<Session, BoxError> {
+        let dirs = directories(file);
+        for depth in 1..=dirs.len() {
+            self.mkcol(&dirs[..depth]).await?;
+        }
+        Ok(Session::default())
+    }
+
+    async fn put_part(&self, file: &FileInfo, _session: &Session, part: Part) -> Result<PartReceipt, BoxError> {
+        let mut segments = directories(file);
+        segments.push(file.name.clone());
+        let url = self.url(&segments, false)?;
+
+        let retry = match &part.body {
+            PartBody::Bytes(bytes) if self.options.digest => Some(bytes.clone()),
+            _ => None,
+        };
+        let mut response = self.put(&url, part.len, part.body).await?;
+        self.check_auth(&response).await;
+        // one more try with a fresh nonce, a streamed body can't be sent again
+        if let (StatusCode::UNAUTHORIZED, Some(bytes)) = (response.status(), retry) {
+            response = self.put(&url, part.len, PartBody::Bytes(bytes)).await?;
+            self.check_auth(&response).await;
+        }
+        match response.status() {
+            status if status.is_success() => {}
+            StatusCode::UNAUTHORIZED => {
+                return Err(Error::Auth("Authentication failed, check --user and --password".to_string()).into());
+            }
+            StatusCode::PRECONDITION_FAILED => {
+                return Err(format!("{} already exists, not overwritten because of --no-overwrite", url).into());
+            }
+            status => return Err(Error::from_status(status, response.text().await.unwrap_or_default()).into()),
+        }
+
+        Ok(PartReceipt {
+            index: part.index,
+            response: Some(url.to_string()),
+            ..Default::default()
+        })
+    }
+}
+
+fn parse_challenge(headers: &HeaderMap) -> Result<WwwAuthenticateHeader, BoxError> {
+    let header = headers
+        .get_all(WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .find(|value| value.trim_start().starts_with("Digest"))
+        .ok_or("The server sent no digest challenge")?;
+
+    let mut challenge = digest_auth::parse(header).map_err(|e| format!("Invalid digest challenge: {}", e))?;
+    // auth-int hashes the body, which is streamed and not known up front
+    if let Some(qop) = &mut challenge.qop {
+        if qop.contains(&Qop::AUTH) {
+            *qop = vec![Qop::AUTH];
+        }
+    }
+    Ok(challenge)
+}
+
+// the collections above the file, from the top
+fn directories(file: &FileInfo) -> Vec<String> {
+    file.relative_path
+        .parent()
+        .into_iter()
+        .flat_map(|parent| parent.components())
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect()
+}
@@ -1,7 +1,22 @@
-use clap::Parser;
+use dup_cli::backend::chunks::{ChunkFormat, SessionPaths};
+use dup_cli::backend::ftp::FtpOptions;
+use dup_cli::backend::s3::S3Options;
+use dup_cli::backend::sftp::SftpOptions;
+use dup_cli::backend::tus::TusOptions;
+use dup_cli::backend::webdav::WebDavOptions;
+use dup_cli::backend::{BackendKind, BackendOptions};
+use dup_cli::client::ClientOptions;
+use dup_cli::crypto::{parse_recipient, DecryptionKey, Encryptor, KeySource, DEFAULT_SEGMENT_SIZE};
+use dup_cli::tls::{parse_pin, TlsOptions, TlsVersion};
+use dup_cli::files::{parse_duration, parse_time, Filter, LastRun};
+use dup_cli::tuning::{ChunkSize, Concurrency};
+use dup_cli::units::{parse_size, Units};
+use clap::{Args, Parser, Subcommand};
 use std::error::Error;
 use std::io::{stdin, BufRead, IsTerminal};
 use std::path::PathBuf;
+use chrono::{DateTime, Local, NaiveTime};
+use std::time::{Duration, SystemTime};
 
 #[derive(Debug, PartialEq, Default)]
 pub enum KindOfUpload {
@@ -31,10 +46,110 @@ impl std::str::FromStr for KindOfUpload {
     }
 }
 
-#[derive(Debug, Parser, Default)]
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Decrypt a file that was uploaded with --encrypt
+    Decrypt {
+        // encrypted file
+        input: PathBuf,
+        // where to write the plaintext, defaults to the input without the .enc suffix
+        #[clap(short = 'o', long = "output")]
+        output: Option<PathBuf>,
+        #[clap(long = "key-file", conflicts_with = "identity")]
+        key_file: Option<PathBuf>,
+        #[clap(long = "passphrase", env = "DUP_PASSPHRASE", hide_env_values = true)]
+        passphrase: Option<String>,
+        // x25519 secret key generated by `keygen --identity`
+        #[clap(long = "identity")]
+        identity: Option<PathBuf>,
+    },
+    /// Upload new and changed files below folders as they are written, until Ctrl-C
+    Watch(Box<WatchArgs>),
+    /// Run, list or remove the uploads queued with --at and --every
+    Schedule {
+        #[clap(subcommand)]
+        action: ScheduleAction,
+    },
+    /// Generate a symmetric key file or an x25519 identity for --encrypt
+    Keygen {
+        #[clap(long = "key-file", conflicts_with = "identity", required_unless_present = "identity")]
+        key_file: Option<PathBuf>,
+        #[clap(long = "identity")]
+        identity: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ScheduleAction {
+    /// Run queued uploads when they are due, and those missed while nothing ran them, until Ctrl-C
+    Run {
+        // run the uploads that are due and exit, e.g. from cron or a systemd timer
+        #[clap(long = "once")]
+        once: bool,
+    },
+    /// List the queued uploads and when they run next
+    List,
+    /// Remove a queued upload
+    Remove { id: String },
+}
+
+impl Command {
+    pub fn decryption_key(
+        key_file: Option<PathBuf>,
+        passphrase: Option<String>,
+        identity: Option<PathBuf>,
+    ) -> Result<DecryptionKey, Box<dyn Error + Send + Sync>> {
+        if explicit_passphrase(&passphrase) && (key_file.is_some() || identity.is_some()) {
+            return Err("Use only one of --key-file, --passphrase and --identity".into());
+        }
+        match (key_file, identity, passphrase) {
+            (Some(path), _, _) => Ok(DecryptionKey::KeyFile(path)),
+            (_, Some(path), _) => Ok(DecryptionKey::Identity(path)),
+            (_, _, Some(passphrase)) => Ok(DecryptionKey::Passphrase(passphrase)),
+            _ => Err("Provide one of --key-file, --passphrase or --identity to decrypt".into()),
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
 #[clap(name = "Doffy uploader CLI", version = "0.1.7", author = "D. Doffy")]
 #[clap(about = "Upload files to server parallelly", long_about = "Upload files to server parallelly, that supports multipart and binarry uploads, with progress bar")]
+#[clap(args_conflicts_with_subcommands = true)]
 pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+    #[clap(flatten)]
+    pub upload: UploadArgs,
+    // queue the upload to run at a time instead of now, e.g. 02:00 or 2024-05-01T02:00:00
+    #[clap(long = "at", value_parser = parse_at, conflicts_with = "dry_run")]
+    pub at: Option<DateTime<Local>>,
+    // queue the upload to run repeatedly, e.g. 1h or 1d, the first time at --at or right away
+    #[clap(long = "every", value_parser = parse_interval, conflicts_with = "dry_run")]
+    pub every: Option<Duration>,
+}
+
+impl Cli {
+    pub fn from_args() -> Self {
+        Self::parse()
+    }
+}
+
+// `dup-cli watch`, the upload options and how files are watched
+#[derive(Debug, Args)]
+pub struct WatchArgs {
+    #[clap(flatten)]
+    pub upload: UploadArgs,
+    // seconds the size of a file has to stay the same before it is uploaded, unless it was closed
+    #[clap(long = "settle", default_value = "2")]
+    pub settle: u64,
+    // file that keeps what was uploaded, so a restart only sends new and changed files
+    #[clap(long = "watch-state")]
+    pub state: Option<PathBuf>,
+}
+
+// the files and where to upload them, shared by uploads and `watch`
+#[derive(Debug, Args, Default)]
+pub struct UploadArgs {
     // host to upload to
     #[clap(short = 'H', long = "host", required = false, default_value = "")]
     pub host: String,
@@ -45,6 +160,9 @@ pub struct Cli {
         required = false
     )]
     pub category: KindOfUpload,
+    // upload target: multipart, binary, chunks, json-chunks, s3, tus, webdav, sftp or ftp, overrides --category
+    #[clap(short = 'b', long = "backend", visible_alias = "protocol")]
+    pub backend: Option<BackendKind>,
     // token to authenticate
     #[clap(short = 't', long = "token", required = false, default_value = "")]
     pub token: String,
@@ -53,26 +171,331 @@ pub struct Cli {
     // show progress
     #[clap(short = 'p', long = "progress", required = false)]
     pub progress: bool,
-    // chunk size
+    // how sizes are printed: iec (KiB, MiB, GiB, TiB), si (kB, MB, GB, TB) or bytes
+    #[clap(long = "units", default_value = "iec")]
+    pub units: Units,
+    // print events and the summary as JSON lines instead of text
+    #[clap(long = "json", required = false, conflicts_with = "progress")]
+    pub json: bool,
+    // chunk size, e.g. 8MiB, or auto to pick one from the measured link
     #[clap(short = 's', long = "chunk-size", required = false)]
-    pub chunk_size: Option<usize>,
+    pub chunk_size: Option<ChunkSize>,
+    // chunks of one file in flight, or auto to find the best number [default: the backend's limit]
+    #[clap(long = "chunk-concurrency")]
+    pub chunk_concurrency: Option<Concurrency>,
+    // upper bound for the chunk buffers of all files together, e.g. 512MiB
+    #[clap(long = "max-memory", value_parser = parse_size)]
+    pub max_memory: Option<u64>,
+    // chunk body: raw (metadata in headers), multipart or json (base64, for older servers)
+    #[clap(long = "chunk-format", default_value = "raw")]
+    pub chunk_format: ChunkFormat,
+    // open chunked uploads with an init call and close them with finalize, or abort on failure
+    #[clap(long = "chunk-session", required = false)]
+    pub chunk_session: bool,
+    // session endpoints below --host, or full URLs
+    #[clap(long = "init-path", default_value = "init")]
+    pub init_path: String,
+    #[clap(long = "finalize-path", default_value = "finalize")]
+    pub finalize_path: String,
+    #[clap(long = "abort-path", default_value = "abort")]
+    pub abort_path: String,
+    // files uploaded at the same time, they share one connection pool
+    #[clap(short = 'j', long = "jobs", default_value = "16")]
+    pub jobs: usize,
+    // only files of at least or at most this size, e.g. 1KiB or 2GB
+    #[clap(long = "min-size", value_parser = parse_size)]
+    pub min_size: Option<u64>,
+    #[clap(long = "max-size", value_parser = parse_size)]
+    pub max_size: Option<u64>,
+    // only files modified after or before a time, e.g. 24h or 7d ago, 2024-05-01 or 2024-05-01T12:00:00
+    #[clap(long = "newer-than", value_parser = parse_time)]
+    pub newer_than: Option<SystemTime>,
+    #[clap(long = "older-than", value_parser = parse_time)]
+    pub older_than: Option<SystemTime>,
+    // only files modified since the last run to this host with these paths that had no failures
+    #[clap(long = "modified-since-last-run", required = false)]
+    pub modified_since_last_run: bool,
+    // only files with one of these extensions, e.g. log,gz
+    #[clap(long = "ext", value_delimiter = ',')]
+    pub extensions: Vec<String>,
+    // no files with one of these extensions
+    #[clap(long = "exclude-ext", value_delimiter = ',')]
+    pub exclude_extensions: Vec<String>,
+    #[clap(skip)]
+    pub filter: Filter,
+    // list the files that would be uploaded and those filtered out, without uploading
+    #[clap(short = 'n', long = "dry-run", required = false)]
+    pub dry_run: bool,
+    // encrypt files client-side before upload
+    #[clap(short = 'e', long = "encrypt", required = false)]
+    pub encrypt: bool,
+    // file holding a 32-byte key (raw, hex or base64)
+    #[clap(long = "key-file", conflicts_with = "recipient")]
+    pub key_file: Option<PathBuf>,
+    #[clap(long = "passphrase", env = "DUP_PASSPHRASE", hide_env_values = true)]
+    pub passphrase: Option<String>,
+    // x25519 public key (base64 or hex) printed by `keygen --identity`
+    #[clap(long = "recipient")]
+    pub recipient: Option<String>,
+    #[clap(skip)]
+    pub key_source: Option<KeySource>,
+    // PEM CA bundle to verify the server with instead of the built-in roots
+    #[clap(long = "cacert")]
+    pub ca_cert: Option<PathBuf>,
+    // PEM client certificate for mutual TLS, used with --key
+    #[clap(long = "cert", requires = "client_key")]
+    pub client_cert: Option<PathBuf>,
+    // PEM private key of the client certificate
+    #[clap(long = "key", requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+    // sha256//<base64> digest of a server public key, can be repeated
+    #[clap(long = "pin-sha256")]
+    pub pins: Vec<String>,
+    // minimum TLS version: 1.2 or 1.3
+    #[clap(long = "tls-min-version")]
+    pub tls_min_version: Option<TlsVersion>,
+    // disable certificate verification, only for testing
+    #[clap(short = 'k', long = "insecure", required = false)]
+    pub insecure: bool,
+    // proxy for all requests: http://, https://, socks5:// or socks5h://
+    #[clap(short = 'x', long = "proxy")]
+    pub proxy: Option<String>,
+    // proxy only for http:// hosts
+    #[clap(long = "http-proxy")]
+    pub http_proxy: Option<String>,
+    // proxy only for https:// hosts
+    #[clap(long = "https-proxy")]
+    pub https_proxy: Option<String>,
+    // comma separated hosts that bypass the proxy, defaults to NO_PROXY
+    #[clap(long = "no-proxy")]
+    pub no_proxy: Option<String>,
+    // seconds to wait for a connection
+    #[clap(long = "connect-timeout")]
+    pub connect_timeout: Option<u64>,
+    // seconds allowed for a whole request, including the upload
+    #[clap(long = "timeout")]
+    pub timeout: Option<u64>,
+    // seconds between TCP keepalive probes
+    #[clap(long = "tcp-keepalive")]
+    pub tcp_keepalive: Option<u64>,
+    // talk HTTP/2 without negotiation
+    #[clap(long = "http2-prior-knowledge", required = false)]
+    pub http2_prior_knowledge: bool,
+    // local IP address to bind outgoing connections to
+    #[clap(long = "local-address")]
+    pub local_address: Option<std::net::IpAddr>,
+    #[clap(skip)]
+    pub client: ClientOptions,
+    // S3 bucket to upload to with --backend s3
+    #[clap(long = "bucket")]
+    pub bucket: Option<String>,
+    // S3 key prefix, e.g. backups/2024
+    #[clap(long = "prefix", default_value = "")]
+    pub prefix: String,
+    #[clap(long = "region", env = "AWS_REGION", default_value = "us-east-1")]
+    pub region: String,
+    #[clap(long = "access-key", env = "AWS_ACCESS_KEY_ID", hide_env_values = true)]
+    pub access_key: Option<String>,
+    #[clap(long = "secret-key", env = "AWS_SECRET_ACCESS_KEY", hide_env_values = true)]
+    pub secret_key: Option<String>,
+    #[clap(long = "session-token", env = "AWS_SESSION_TOKEN", hide_env_values = true)]
+    pub session_token: Option<String>,
+    // address buckets as bucket.host instead of host/bucket
+    #[clap(long = "s3-virtual-hosted", required = false)]
+    pub s3_virtual_hosted: bool,
+    // file that keeps tus upload URLs so interrupted uploads resume on the next run
+    #[clap(long = "tus-state")]
+    pub tus_state: Option<PathBuf>,
+    // file that keeps which FTP uploads were interrupted, only those are continued on the next run
+    #[clap(long = "ftp-state")]
+    pub ftp_state: Option<PathBuf>,
+    // always start tus and FTP uploads from the beginning
+    #[clap(long = "no-resume", required = false)]
+    pub no_resume: bool,
+    // WebDAV, SFTP and FTP credentials as user:password, the password may also come from DUP_PASSWORD
+    #[clap(short = 'u', long = "user")]
+    pub user: Option<String>,
+    #[clap(long = "password", env = "DUP_PASSWORD", hide_env_values = true)]
+    pub password: Option<String>,
+    // use digest instead of basic authentication
+    #[clap(long = "digest", required = false)]
+    pub digest: bool,
+    // fail instead of replacing files that already exist on a WebDAV server
+    #[clap(long = "no-overwrite", required = false)]
+    pub no_overwrite: bool,
+    // SSH private key for sftp://, the agent and ~/.ssh/id_* are tried without it
+    #[clap(long = "ssh-key")]
+    pub ssh_key: Option<PathBuf>,
+    #[clap(long = "ssh-key-passphrase", env = "DUP_SSH_KEY_PASSPHRASE", hide_env_values = true)]
+    pub ssh_key_passphrase: Option<String>,
+    // known_hosts file the server key is checked against, defaults to ~/.ssh/known_hosts
+    #[clap(long = "known-hosts")]
+    pub known_hosts: Option<PathBuf>,
+    // explicit FTPS: AUTH TLS before logging in, with the --cacert and --pin-sha256 settings
+    #[clap(long = "ftps", required = false)]
+    pub ftps: bool,
+    #[clap(skip)]
+    pub backend_options: BackendOptions,
 }
 
-impl Cli {
-    pub fn from_args() -> Self {
-        Self::parse()
+impl UploadArgs {
+    // --backend wins, then the scheme of --host, otherwise --chunk-size picks
+    // chunks and --category the rest
+    pub fn backend_kind(&self) -> BackendKind {
+        match (&self.backend, self.chunk_size, &self.category) {
+            (Some(backend), _, _) => backend.clone(),
+            (None, _, _) if self.host.starts_with("sftp://") => BackendKind::Sftp,
+            (None, _, _) if self.host.starts_with("ftp://") => BackendKind::Ftp,
+            (None, Some(_), _) => BackendKind::Chunks,
+            (None, None, KindOfUpload::Multipart) => BackendKind::Multipart,
+            (None, None, KindOfUpload::Binary) => BackendKind::Binary,
+        }
     }
 
-    pub fn validate(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn validate(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
         if self.host.is_empty() {
             self.host = match std::env::var("UPLOAD_URL") {
                 Ok(host) => host,
+                // AWS itself when no S3 compatible endpoint is given
+                Err(_) if self.backend_kind() == BackendKind::S3 => {
+                    format!("https://s3.{}.amazonaws.com", self.region)
+                }
+                // nothing is sent
+                Err(_) if self.dry_run => String::new(),
                 Err(_) => {
                     return Err("No host provided. Please provide a host using the --host flag or UPLOAD_URL environment variable".into());
                 }
             };
         }
 
+        if self.chunk_size == Some(ChunkSize::Fixed(0)) {
+            return Err("--chunk-size must be at least 1".into());
+        }
+
+        if self.jobs == 0 {
+            return Err("--jobs must be at least 1".into());
+        }
+
+        if explicit_passphrase(&self.passphrase) && (self.key_file.is_some() || self.recipient.is_some()) {
+            return Err("Use only one of --key-file, --passphrase and --recipient".into());
+        }
+        self.key_source = match (&self.key_file, &self.recipient, &self.passphrase) {
+            (Some(path), _, _) => Some(KeySource::KeyFile(path.clone())),
+            (_, Some(recipient), _) => Some(KeySource::Recipient(parse_recipient(recipient)?)),
+            (_, _, Some(passphrase)) => Some(KeySource::Passphrase(passphrase.clone())),
+            _ => None,
+        };
+
+        if self.encrypt {
+            match &self.key_source {
+                // fail fast on unreadable keys instead of once per file
+                Some(key_source) => {
+                    Encryptor::new(key_source, DEFAULT_SEGMENT_SIZE)?;
+                }
+                None => {
+                    return Err("--encrypt needs one of --key-file, --passphrase or --recipient".into());
+                }
+            }
+        }
+
+        if !self.encrypt {
+            // DUP_PASSPHRASE may be exported for `decrypt`, only explicit key
+            // options are a mistake without --encrypt
+            if self.key_file.is_some() || self.recipient.is_some() {
+                return Err("--key-file and --recipient are only used together with --encrypt".into());
+            }
+            self.key_source = None;
+        }
+
+        let tls = TlsOptions {
+            ca_cert: self.ca_cert.clone(),
+            client_cert: self.client_cert.clone(),
+            client_key: self.client_key.clone(),
+            pins: self
+                .pins
+                .iter()
+                .map(|pin| parse_pin(pin))
+                .collect::<Result<Vec<_>, _>>()?,
+            min_version: self.tls_min_version,
+            insecure: self.insecure,
+        };
+
+        let seconds = |value: Option<u64>| value.map(std::time::Duration::from_secs);
+        self.client = ClientOptions {
+            tls,
+            proxy: self.proxy.clone(),
+            http_proxy: self.http_proxy.clone(),
+            https_proxy: self.https_proxy.clone(),
+            no_proxy: self.no_proxy.clone(),
+            connect_timeout: seconds(self.connect_timeout),
+            timeout: seconds(self.timeout),
+            tcp_keepalive: seconds(self.tcp_keepalive),
+            http2_prior_knowledge: self.http2_prior_knowledge,
+            local_address: self.local_address,
+        };
+
+        // surface certificate, key and proxy problems once, before any upload starts
+        self.client.build()?;
+
+        let (user, password) = match self.user.as_deref().map(|user| user.split_once(':')) {
+            Some(Some((user, password))) => (Some(user.to_string()), Some(password.to_string())),
+            Some(None) => (self.user.clone(), self.password.clone()),
+            None => (None, None),
+        };
+
+        self.backend_options = BackendOptions {
+            chunk_size: match self.chunk_size {
+                Some(ChunkSize::Fixed(chunk_size)) => Some(chunk_size),
+                _ => None,
+            },
+            chunk_format: self.chunk_format,
+            chunk_session: self.chunk_session.then(|| SessionPaths {
+                init: self.init_path.clone(),
+                finalize: self.finalize_path.clone(),
+                abort: self.abort_path.clone(),
+            }),
+            s3: S3Options {
+                bucket: self.bucket.clone().unwrap_or_default(),
+                prefix: self.prefix.clone(),
+                region: self.region.clone(),
+                access_key: self.access_key.clone().unwrap_or_default(),
+                secret_key: self.secret_key.clone().unwrap_or_default(),
+                session_token: self.session_token.clone(),
+                virtual_hosted: self.s3_virtual_hosted,
+            },
+            tus: TusOptions {
+                state_file: match self.no_resume {
+                    true => None,
+                    false => self.tus_state.clone().or_else(TusOptions::default_state_file),
+                },
+            },
+            sftp: SftpOptions {
+                user: user.clone(),
+                password: password.clone(),
+                key: self.ssh_key.clone(),
+                key_passphrase: self.ssh_key_passphrase.clone(),
+                known_hosts: self.known_hosts.clone(),
+                connect_timeout: self.client.connect_timeout,
+            },
+            ftp: FtpOptions {
+                user: user.clone(),
+                password: password.clone(),
+                explicit_tls: self.ftps,
+                tls: self.client.tls.clone(),
+                connect_timeout: self.client.connect_timeout,
+                state_file: match self.no_resume {
+                    true => None,
+                    false => self.ftp_state.clone().or_else(FtpOptions::default_state_file),
+                },
+            },
+            webdav: WebDavOptions {
+                user,
+                password,
+                digest: self.digest,
+                no_overwrite: self.no_overwrite,
+            },
+        };
+
         if self.paths.is_empty() {
             if stdin().is_terminal() {
                 return Err("No files or directories provided".into());
@@ -86,6 +509,129 @@ impl Cli {
                 .collect();
         }
 
+        if let (Some(min_size), Some(max_size)) = (self.min_size, self.max_size) {
+            if min_size > max_size {
+                return Err("--min-size is larger than --max-size".into());
+            }
+        }
+        let extensions = |extensions: &[String]| {
+            extensions
+                .iter()
+                .map(|extension| extension.trim().trim_start_matches('.').to_string())
+                .filter(|extension| !extension.is_empty())
+                .collect()
+        };
+        self.filter = Filter {
+            min_size: self.min_size,
+            max_size: self.max_size,
+            newer_than: self.newer_than,
+            older_than: self.older_than,
+            extensions: extensions(&self.extensions),
+            exclude_extensions: extensions(&self.exclude_extensions),
+        };
+        if self.modified_since_last_run {
+            let last_run = LastRun::default_file()
+                .map(LastRun::new)
+                .and_then(|last_run| last_run.get(&self.last_run_key()));
+            // the later of the two cutoffs
+            self.filter.newer_than = self.filter.newer_than.max(last_run);
+        }
+
         Ok(())
     }
+
+    // runs to the same host with the same paths share their last run time
+    pub fn last_run_key(&self) -> String {
+        let paths: Vec<String> = self.paths.iter().map(|path| path.display().to_string()).collect();
+        format!("{} {}", self.host, paths.join(" "))
+    }
+}
+
+// `02:00` or `02:00:00` the next time it comes, `2h` from now, or a date and time as for --newer-than
+fn parse_at(s: &str) -> Result<DateTime<Local>, String> {
+    at_after(s, Local::now())
+}
+
+fn at_after(s: &str, now: DateTime<Local>) -> Result<DateTime<Local>, String> {
+    if let Some(after) = parse_duration(s).and_then(|after| chrono::Duration::from_std(after).ok()) {
+        return Ok(now + after);
+    }
+    if let Ok(time) = NaiveTime::parse_from_str(s, "%H:%M").or_else(|_| NaiveTime::parse_from_str(s, "%H:%M:%S")) {
+        let mut date = now.date_naive();
+        // a day later when it passed today, or doesn't exist today because of a clock change
+        for _ in 0..3 {
+            if let Some(at) = date.and_time(time).and_local_timezone(Local).earliest().filter(|at| *at > now) {
+                return Ok(at);
+            }
+            date = date.succ_opt().ok_or("Invalid time")?;
+        }
+    }
+    let at: DateTime<Local> = parse_time(s)?.into();
+    if at <= now {
+        return Err(format!("{} is in the past", s));
+    }
+    Ok(at)
+}
+
+// --passphrase given as an option; one from DUP_PASSPHRASE, which may be exported for
+// `decrypt`, gives way to an explicit --key-file, --recipient or --identity
+fn explicit_passphrase(passphrase: &Option<String>) -> bool {
+    passphrase.is_some() && std::env::var("DUP_PASSPHRASE").ok() != *passphrase
+}
+
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    match parse_duration(s) {
+        Some(interval) if !interval.is_zero() => Ok(interval),
+        _ => Err(format!("Invalid interval {}, expected e.g. 30m, 1h or 1d", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, day, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn times_of_day_come_next() {
+        let now = at(1, 12, 0);
+        assert_eq!(at_after("13:00", now), Ok(at(1, 13, 0)));
+        assert_eq!(at_after("13:00:30", now), Ok(at(1, 13, 0) + chrono::Duration::seconds(30)));
+        // passed today, or right now
+        assert_eq!(at_after("02:00", now), Ok(at(2, 2, 0)));
+        assert_eq!(at_after("12:00", now), Ok(at(2, 12, 0)));
+        // over the end of the month
+        let end_of_month = Local.with_ymd_and_hms(2024, 5, 31, 23, 0, 0).unwrap();
+        assert_eq!(at_after("02:00", end_of_month), Ok(Local.with_ymd_and_hms(2024, 6, 1, 2, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn durations_are_from_now() {
+        let now = at(1, 12, 0);
+        assert_eq!(at_after("2h", now), Ok(at(1, 14, 0)));
+        assert_eq!(at_after("1d", now), Ok(at(2, 12, 0)));
+    }
+
+    #[test]
+    fn dates_must_be_ahead() {
+        let now = at(1, 12, 0);
+        assert_eq!(at_after("2024-05-03 08:30", now), Ok(at(3, 8, 30)));
+        assert_eq!(at_after("2024-05-02", now), Ok(at(2, 0, 0)));
+        let error = at_after("2024-05-01T11:00:00", now).unwrap_err();
+        assert!(error.contains("in the past"), "{}", error);
+        assert!(at_after("2024-04-30", now).is_err());
+        assert!(at_after("25:00", now).is_err());
+        assert!(at_after("tomorrow", now).is_err());
+    }
+
+    #[test]
+    fn intervals_are_not_zero() {
+        assert_eq!(parse_interval("1h"), Ok(Duration::from_secs(60 * 60)));
+        assert_eq!(parse_interval("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert!(parse_interval("0m").is_err());
+        assert!(parse_interval("02:00").is_err());
+    }
 }
@@ -0,0 +1,73 @@
+use crate::tls::TlsOptions;
+use reqwest::{Client, NoProxy, Proxy};
+use std::error::Error;
+use std::net::IpAddr;
+use std::time::Duration;
+
+// everything that shapes the HTTP client, kept apart from per-upload settings
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    pub tls: TlsOptions,
+    // proxy for every scheme: http://, https://, socks5:// or socks5h://
+    pub proxy: Option<String>,
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    // comma separated hosts that bypass the proxies, NO_PROXY is used when unset
+    pub no_proxy: Option<String>,
+    pub connect_timeout: Option<Duration>,
+    // limit for a whole request, including the upload of the body
+    pub timeout: Option<Duration>,
+    pub tcp_keepalive: Option<Duration>,
+    pub http2_prior_knowledge: bool,
+    pub local_address: Option<IpAddr>,
+}
+
+impl ClientOptions {
+    pub fn build(&self) -> Result<Client, Box<dyn Error + Send + Sync>> {
+        let mut builder = Client::builder();
+
+        if !self.tls.is_default() {
+            builder = builder.use_preconfigured_tls(self.tls.client_config()?);
+        }
+
+        // explicit proxies replace the HTTP_PROXY / HTTPS_PROXY / ALL_PROXY
+        // lookup reqwest does on its own, so NO_PROXY has to be carried over
+        let no_proxy = match &self.no_proxy {
+            Some(list) => NoProxy::from_string(list),
+            None => NoProxy::from_env(),
+        };
+        let proxy = |url: &String, proxy: reqwest::Result<Proxy>| {
+            proxy
+                .map(|proxy| proxy.no_proxy(no_proxy.clone()))
+                .map_err(|e| format!("Invalid proxy {}: {}", url, e))
+        };
+        // scheme specific proxies first, the first matching one is used
+        if let Some(url) = &self.http_proxy {
+            builder = builder.proxy(proxy(url, Proxy::http(url))?);
+        }
+        if let Some(url) = &self.https_proxy {
+            builder = builder.proxy(proxy(url, Proxy::https(url))?);
+        }
+        if let Some(url) = &self.proxy {
+            builder = builder.proxy(proxy(url, Proxy::all(url))?);
+        }
+
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(keepalive) = self.tcp_keepalive {
+            builder = builder.tcp_keepalive(keepalive);
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(address) = self.local_address {
+            builder = builder.local_address(address);
+        }
+
+        Ok(builder.build()?)
+    }
+}
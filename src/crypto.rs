@@ -0,0 +1,573 @@
+use base64::{engine::general_purpose, Engine};
+use bytes::Bytes;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use futures::Stream;
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+// envelope layout:
+//   magic (7) | key kind (1) | segment size u32 BE (4) | nonce prefix (19) | key material
+// followed by segments of `segment size` plaintext bytes, each sealed with
+// XChaCha20-Poly1305 under nonce = prefix | segment index u32 BE | last flag,
+// and the header as associated data.
+pub const MAGIC: &[u8; 7] = b"DUPENC1";
+pub const DEFAULT_SEGMENT_SIZE: usize = 64 * 1024;
+pub const TAG_SIZE: usize = 16;
+pub const ENCRYPTED_SUFFIX: &str = ".enc";
+
+const NONCE_PREFIX_SIZE: usize = 19;
+const SALT_SIZE: usize = 16;
+const KIND_KEY_FILE: u8 = 1;
+const KIND_PASSPHRASE: u8 = 2;
+const KIND_RECIPIENT: u8 = 3;
+const RECIPIENT_INFO: &[u8] = b"dup-cli x25519 v1";
+
+#[derive(Clone)]
+pub enum KeySource {
+    // raw 32-byte key, stored as hex or base64 in a file
+    KeyFile(PathBuf),
+    Passphrase(String),
+    // x25519 public key of the recipient
+    Recipient([u8; 32]),
+}
+
+impl std::fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeySource::KeyFile(path) => f.debug_tuple("KeyFile").field(path).finish(),
+            KeySource::Passphrase(_) => f.write_str("Passphrase(..)"),
+            KeySource::Recipient(key) => f
+                .debug_tuple("Recipient")
+                .field(&general_purpose::STANDARD.encode(key))
+                .finish(),
+        }
+    }
+}
+
+// what is needed to open an envelope, the counterpart of `KeySource`
+#[derive(Clone)]
+pub enum DecryptionKey {
+    KeyFile(PathBuf),
+    Passphrase(String),
+    Identity(PathBuf),
+}
+
+pub struct Encryptor {
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    segment_size: usize,
+    header: Vec<u8>,
+}
+
+impl std::fmt::Debug for Encryptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Encryptor")
+            .field("segment_size", &self.segment_size)
+            .field("header_len", &self.header.len())
+            .finish()
+    }
+}
+
+impl Encryptor {
+    // every call produces a fresh nonce prefix (and salt or ephemeral key), so
+    // one encryptor must be created per uploaded file
+    pub fn new(source: &KeySource, segment_size: usize) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if segment_size == 0 || segment_size > u32::MAX as usize {
+            return Err("Invalid encryption segment size".into());
+        }
+
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut nonce_prefix);
+
+        let (kind, key, material) = match source {
+            KeySource::KeyFile(path) => (KIND_KEY_FILE, read_key_file(path)?, vec![]),
+            KeySource::Passphrase(passphrase) => {
+                let mut salt = [0u8; SALT_SIZE];
+                OsRng.fill_bytes(&mut salt);
+                let key = derive_passphrase_key(passphrase, &salt)?;
+                (KIND_PASSPHRASE, key, salt.to_vec())
+            }
+            KeySource::Recipient(recipient) => {
+                let recipient = PublicKey::from(*recipient);
+                let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+                let ephemeral_public = PublicKey::from(&ephemeral);
+                let shared = ephemeral.diffie_hellman(&recipient);
+                let key = derive_recipient_key(
+                    shared.as_bytes(),
+                    ephemeral_public.as_bytes(),
+                    recipient.as_bytes(),
+                )?;
+                (KIND_RECIPIENT, key, ephemeral_public.as_bytes().to_vec())
+            }
+        };
+
+        let mut header = Vec::with_capacity(MAGIC.len() + 1 + 4 + NONCE_PREFIX_SIZE + material.len());
+        header.extend_from_slice(MAGIC);
+        header.push(kind);
+        header.extend_from_slice(&(segment_size as u32).to_be_bytes());
+        header.extend_from_slice(&nonce_prefix);
+        header.extend_from_slice(&material);
+
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(&key),
+            nonce_prefix,
+            segment_size,
+            header,
+        })
+    }
+
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    pub fn segment_size(&self) -> usize {
+        self.segment_size
+    }
+
+    pub fn total_segments(&self, plaintext_len: u64) -> u64 {
+        // an empty file is still sealed as one (empty) final segment
+        plaintext_len.div_ceil(self.segment_size as u64).max(1)
+    }
+
+    pub fn encrypted_len(&self, plaintext_len: u64) -> u64 {
+        self.header.len() as u64 + plaintext_len + self.total_segments(plaintext_len) * TAG_SIZE as u64
+    }
+
+    // seals `buffer` in place as segment `index`; the index and the final
+    // segment flag are part of the nonce, so reordered, dropped or truncated
+    // segments fail authentication on decrypt
+    pub fn encrypt_segment(&self, index: u64, last: bool, buffer: &mut Vec<u8>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let nonce = segment_nonce(&self.nonce_prefix, index, last)?;
+        self.cipher
+            .encrypt_in_place(&nonce, &self.header, buffer)
+            .map_err(|_| "Failed to encrypt segment")?;
+        Ok(())
+    }
+
+    // streams the envelope for a reader of `plaintext_len` bytes: the header,
+    // then one sealed segment per `segment_size` bytes of input
+    pub fn encrypt_stream<R>(
+        self: Arc<Self>,
+        reader: R,
+        plaintext_len: u64,
+    ) -> impl Stream<Item = Result<Bytes, std::io::Error>>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let total_segments = self.total_segments(plaintext_len);
+
+        futures::stream::unfold(
+            (self, reader, 0u64, false),
+            move |(encryptor, mut reader, index, header_sent)| async move {
+                if !header_sent {
+                    let header = Bytes::copy_from_slice(encryptor.header());
+                    return Some((Ok(header), (encryptor, reader, index, true)));
+                }
+
+                if index >= total_segments {
+                    return None;
+                }
+
+                let mut buffer = Vec::with_capacity(encryptor.segment_size + TAG_SIZE);
+                if let Err(e) = (&mut reader)
+                    .take(encryptor.segment_size as u64)
+                    .read_to_end(&mut buffer)
+                    .await
+                {
+                    return Some((Err(e), (encryptor, reader, total_segments, true)));
+                }
+
+                let last = index + 1 == total_segments;
+                if let Err(e) = encryptor.encrypt_segment(index, last, &mut buffer) {
+                    let err = std::io::Error::other(e.to_string());
+                    return Some((Err(err), (encryptor, reader, total_segments, true)));
+                }
+
+                Some((Ok(Bytes::from(buffer)), (encryptor, reader, index + 1, true)))
+            },
+        )
+    }
+}
+
+// decrypts an envelope produced by `Encryptor`, returns the plaintext size
+pub fn decrypt_file(input: &Path, output: &Path, key: &DecryptionKey) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let total_len = std::fs::metadata(input)?.len();
+    let mut reader = std::io::BufReader::new(std::fs::File::open(input)?);
+
+    let mut fixed = [0u8; 7 + 1 + 4 + NONCE_PREFIX_SIZE];
+    reader
+        .read_exact(&mut fixed)
+        .map_err(|_| "File is too short to be encrypted by dup-cli")?;
+    if &fixed[..MAGIC.len()] != MAGIC {
+        return Err("File is not encrypted by dup-cli".into());
+    }
+
+    let kind = fixed[MAGIC.len()];
+    let segment_size = u32::from_be_bytes(fixed[8..12].try_into()?) as usize;
+    if segment_size == 0 {
+        return Err("Invalid segment size in header".into());
+    }
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+    nonce_prefix.copy_from_slice(&fixed[12..]);
+
+    let mut header = fixed.to_vec();
+    let file_key = match (kind, key) {
+        (KIND_KEY_FILE, DecryptionKey::KeyFile(path)) => read_key_file(path)?,
+        (KIND_PASSPHRASE, DecryptionKey::Passphrase(passphrase)) => {
+            let mut salt = [0u8; SALT_SIZE];
+            reader.read_exact(&mut salt)?;
+            header.extend_from_slice(&salt);
+            derive_passphrase_key(passphrase, &salt)?
+        }
+        (KIND_RECIPIENT, DecryptionKey::Identity(path)) => {
+            let mut ephemeral_public = [0u8; 32];
+            reader.read_exact(&mut ephemeral_public)?;
+            header.extend_from_slice(&ephemeral_public);
+            let identity = read_identity(path)?;
+            let public = PublicKey::from(&identity);
+            let shared = identity.diffie_hellman(&PublicKey::from(ephemeral_public));
+            derive_recipient_key(shared.as_bytes(), &ephemeral_public, public.as_bytes())?
+        }
+        (KIND_KEY_FILE, _) => return Err("File was encrypted with a key file, use --key-file".into()),
+        (KIND_PASSPHRASE, _) => return Err("File was encrypted with a passphrase, use --passphrase".into()),
+        (KIND_RECIPIENT, _) => return Err("File was encrypted for a recipient, use --identity".into()),
+        _ => return Err(format!("Unknown key kind {} in header", kind).into()),
+    };
+
+    let cipher = XChaCha20Poly1305::new(&file_key);
+    let sealed_size = (segment_size + TAG_SIZE) as u64;
+    let body_len = total_len - header.len() as u64;
+    let total_segments = body_len.div_ceil(sealed_size);
+    // every segment, including a short final one, carries a full tag
+    let remainder = body_len % sealed_size;
+    if total_segments == 0 || (1..TAG_SIZE as u64).contains(&remainder) {
+        return Err("Encrypted file is truncated".into());
+    }
+
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(output)?);
+    let mut written = 0;
+    for index in 0..total_segments {
+        let mut buffer = Vec::with_capacity(sealed_size as usize);
+        (&mut reader).take(sealed_size).read_to_end(&mut buffer)?;
+
+        let last = index + 1 == total_segments;
+        let nonce = segment_nonce(&nonce_prefix, index, last)?;
+        cipher
+            .decrypt_in_place(&nonce, &header, &mut buffer)
+            .map_err(|_| format!("Authentication failed for segment {}, wrong key or corrupted file", index))?;
+
+        writer.write_all(&buffer)?;
+        written += buffer.len() as u64;
+    }
+    writer.flush()?;
+
+    Ok(written)
+}
+
+// writes a new x25519 identity to `path`, returns the base64 public key
+pub fn generate_identity(path: &Path) -> Result<String, Box<dyn Error + Send + Sync>> {
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = general_purpose::STANDARD.encode(PublicKey::from(&secret).as_bytes());
+
+    let contents = format!(
+        "# dup-cli identity, public key: {}\n{}\n",
+        public,
+        general_purpose::STANDARD.encode(secret.as_bytes())
+    );
+    write_private_file(path, contents.as_bytes())?;
+
+    Ok(public)
+}
+
+// writes a new random 32-byte symmetric key, hex encoded, to `path`
+pub fn generate_key_file(path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    let contents: String = key.iter().map(|b| format!("{:02x}", b)).collect();
+    write_private_file(path, format!("{}\n", contents).as_bytes())
+}
+
+pub fn parse_recipient(value: &str) -> Result<[u8; 32], Box<dyn Error + Send + Sync>> {
+    decode_key(value.trim()).map_err(|_| "Recipient must be a base64 or hex encoded x25519 public key".into())
+}
+
+fn write_private_file(path: &Path, contents: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)?;
+    Ok(())
+}
+
+fn segment_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], index: u64, last: bool) -> Result<XNonce, Box<dyn Error + Send + Sync>> {
+    let index: u32 = index
+        .try_into()
+        .map_err(|_| "Too many segments for one encrypted file")?;
+
+    let mut nonce = XNonce::default();
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..NONCE_PREFIX_SIZE + 4].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_PREFIX_SIZE + 4] = last as u8;
+    Ok(nonce)
+}
+
+fn read_key_file(path: &Path) -> Result<Key, Box<dyn Error + Send + Sync>> {
+    let contents = std::fs::read(path)?;
+    // raw 32 bytes, or a single line of hex or base64
+    if contents.len() == 32 {
+        return Ok(*Key::from_slice(&contents));
+    }
+
+    let text = String::from_utf8(contents).map_err(|_| "Key file must contain 32 raw bytes, hex or base64")?;
+    let bytes = decode_key(text.trim()).map_err(|_| "Key file must contain 32 raw bytes, hex or base64")?;
+    Ok(*Key::from_slice(&bytes))
+}
+
+fn read_identity(path: &Path) -> Result<StaticSecret, Box<dyn Error + Send + Sync>> {
+    let contents = std::fs::read_to_string(path)?;
+    let line = contents
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .ok_or("Identity file is empty")?;
+    let bytes = decode_key(line).map_err(|_| "Identity file must contain a base64 or hex encoded x25519 secret key")?;
+    Ok(StaticSecret::from(bytes))
+}
+
+fn decode_key(value: &str) -> Result<[u8; 32], ()> {
+    let bytes = if value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        (0..64)
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&value[i..i + 2], 16).map_err(|_| ()))
+            .collect::<Result<Vec<u8>, ()>>()?
+    } else {
+        general_purpose::STANDARD.decode(value).map_err(|_| ())?
+    };
+    bytes.try_into().map_err(|_| ())
+}
+
+fn derive_passphrase_key(passphrase: &str, salt: &[u8]) -> Result<Key, Box<dyn Error + Send + Sync>> {
+    let mut key = Key::default();
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Failed to derive key from passphrase: {}", e))?;
+    Ok(key)
+}
+
+fn derive_recipient_key(shared: &[u8], ephemeral_public: &[u8], recipient: &[u8]) -> Result<Key, Box<dyn Error + Send + Sync>> {
+    let salt = [ephemeral_public, recipient].concat();
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(RECIPIENT_INFO, &mut key)
+        .map_err(|_| "Failed to derive recipient key")?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    // a folder of its own per test, removed on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("dup-cli-crypto-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn key_file(dir: &TempDir) -> PathBuf {
+        let path = dir.0.join("key");
+        generate_key_file(&path).unwrap();
+        path
+    }
+
+    // the envelope as the chunked uploads build it, one segment per chunk
+    fn seal(encryptor: &Encryptor, data: &[u8]) -> Vec<u8> {
+        let total = encryptor.total_segments(data.len() as u64);
+        let mut envelope = encryptor.header().to_vec();
+        for index in 0..total {
+            let start = index as usize * encryptor.segment_size();
+            let end = (start + encryptor.segment_size()).min(data.len());
+            let mut buffer = data[start..end].to_vec();
+            encryptor.encrypt_segment(index, index + 1 == total, &mut buffer).unwrap();
+            envelope.extend_from_slice(&buffer);
+        }
+        assert_eq!(envelope.len() as u64, encryptor.encrypted_len(data.len() as u64));
+        envelope
+    }
+
+    fn open(dir: &TempDir, envelope: &[u8], key: &DecryptionKey) -> Result<Vec<u8>, String> {
+        let input = dir.0.join("file.enc");
+        let output = dir.0.join("file");
+        std::fs::write(&input, envelope).unwrap();
+        let written = decrypt_file(&input, &output, key).map_err(|e| e.to_string())?;
+        let plaintext = std::fs::read(&output).unwrap();
+        assert_eq!(written, plaintext.len() as u64);
+        Ok(plaintext)
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("round-trip");
+        let key = key_file(&dir);
+        let encryptor = Encryptor::new(&KeySource::KeyFile(key.clone()), 16).unwrap();
+        // empty, shorter than a segment, exactly one, a whole number and a partial last one
+        for len in [0, 5, 16, 64, 70] {
+            let plaintext = data(len);
+            let envelope = seal(&encryptor, &plaintext);
+            assert_eq!(open(&dir, &envelope, &DecryptionKey::KeyFile(key.clone())).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn round_trip_for_a_recipient() {
+        let dir = TempDir::new("recipient");
+        let identity = dir.0.join("identity");
+        let public = generate_identity(&identity).unwrap();
+        let encryptor = Encryptor::new(&KeySource::Recipient(parse_recipient(&public).unwrap()), 32).unwrap();
+        let plaintext = data(100);
+        let envelope = seal(&encryptor, &plaintext);
+        assert_eq!(open(&dir, &envelope, &DecryptionKey::Identity(identity)).unwrap(), plaintext);
+    }
+
+    #[tokio::test]
+    async fn stream_matches_segments() {
+        let dir = TempDir::new("stream");
+        let key = key_file(&dir);
+        let encryptor = Arc::new(Encryptor::new(&KeySource::KeyFile(key.clone()), 16).unwrap());
+        let plaintext = data(40);
+        let envelope: Vec<u8> = encryptor
+            .clone()
+            .encrypt_stream(std::io::Cursor::new(plaintext.clone()), plaintext.len() as u64)
+            .map(|bytes| bytes.unwrap().to_vec())
+            .concat()
+            .await;
+        assert_eq!(envelope.len() as u64, encryptor.encrypted_len(plaintext.len() as u64));
+        assert_eq!(envelope, seal(&encryptor, &plaintext));
+        assert_eq!(open(&dir, &envelope, &DecryptionKey::KeyFile(key)).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn tampered_segment_fails() {
+        let dir = TempDir::new("tampered");
+        let key = key_file(&dir);
+        let encryptor = Encryptor::new(&KeySource::KeyFile(key.clone()), 16).unwrap();
+        let mut envelope = seal(&encryptor, &data(48));
+        // a byte of the second segment
+        let header_len = encryptor.header().len();
+        envelope[header_len + 16 + TAG_SIZE + 3] ^= 1;
+        let error = open(&dir, &envelope, &DecryptionKey::KeyFile(key)).unwrap_err();
+        assert!(error.contains("segment 1"), "{}", error);
+    }
+
+    #[test]
+    fn tampered_header_fails() {
+        let dir = TempDir::new("header");
+        let key = key_file(&dir);
+        let encryptor = Encryptor::new(&KeySource::KeyFile(key.clone()), 16).unwrap();
+        let mut envelope = seal(&encryptor, &data(20));
+        // the nonce prefix is authenticated as part of the header
+        envelope[MAGIC.len() + 5] ^= 1;
+        assert!(open(&dir, &envelope, &DecryptionKey::KeyFile(key)).is_err());
+    }
+
+    #[test]
+    fn reordered_segments_fail() {
+        let dir = TempDir::new("reordered");
+        let key = key_file(&dir);
+        let encryptor = Encryptor::new(&KeySource::KeyFile(key.clone()), 16).unwrap();
+        let mut envelope = seal(&encryptor, &data(48));
+        let header_len = encryptor.header().len();
+        let sealed = 16 + TAG_SIZE;
+        let (first, second) = envelope[header_len..header_len + 2 * sealed].split_at_mut(sealed);
+        first.swap_with_slice(second);
+        assert!(open(&dir, &envelope, &DecryptionKey::KeyFile(key)).is_err());
+    }
+
+    #[test]
+    fn truncated_at_a_segment_boundary_fails() {
+        let dir = TempDir::new("truncated");
+        let key = key_file(&dir);
+        let encryptor = Encryptor::new(&KeySource::KeyFile(key.clone()), 16).unwrap();
+        let envelope = seal(&encryptor, &data(48));
+        let header_len = encryptor.header().len();
+        // without the last segment the one before it was not sealed as the last one
+        let truncated = &envelope[..header_len + 2 * (16 + TAG_SIZE)];
+        let error = open(&dir, truncated, &DecryptionKey::KeyFile(key.clone())).unwrap_err();
+        assert!(error.contains("segment 1"), "{}", error);
+        // nothing but the header
+        let error = open(&dir, &envelope[..header_len], &DecryptionKey::KeyFile(key.clone())).unwrap_err();
+        assert!(error.contains("truncated"), "{}", error);
+        // part of a tag
+        let error = open(&dir, &envelope[..envelope.len() - (TAG_SIZE + 4)], &DecryptionKey::KeyFile(key)).unwrap_err();
+        assert!(error.contains("truncated"), "{}", error);
+    }
+
+    #[test]
+    fn wrong_key_kind_is_named() {
+        let dir = TempDir::new("kind");
+        let key = key_file(&dir);
+        let encryptor = Encryptor::new(&KeySource::KeyFile(key), 16).unwrap();
+        let envelope = seal(&encryptor, &data(20));
+        let error = open(&dir, &envelope, &DecryptionKey::Passphrase("secret".to_string())).unwrap_err();
+        assert!(error.contains("--key-file"), "{}", error);
+
+        let encryptor = Encryptor::new(&KeySource::Passphrase("secret".to_string()), 16).unwrap();
+        let envelope = seal(&encryptor, &data(20));
+        let error = open(&dir, &envelope, &DecryptionKey::KeyFile(dir.0.join("key"))).unwrap_err();
+        assert!(error.contains("--passphrase"), "{}", error);
+    }
+
+    #[test]
+    fn wrong_key_fails() {
+        let dir = TempDir::new("wrong-key");
+        let encryptor = Encryptor::new(&KeySource::Passphrase("secret".to_string()), 16).unwrap();
+        let envelope = seal(&encryptor, &data(20));
+        assert!(open(&dir, &envelope, &DecryptionKey::Passphrase("other".to_string())).is_err());
+    }
+
+    #[test]
+    fn not_an_envelope_is_rejected() {
+        let dir = TempDir::new("magic");
+        let key = DecryptionKey::Passphrase("secret".to_string());
+        let error = open(&dir, b"plain text, long enough to fill a header", &key).unwrap_err();
+        assert!(error.contains("not encrypted"), "{}", error);
+        let error = open(&dir, b"short", &key).unwrap_err();
+        assert!(error.contains("too short"), "{}", error);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::backend::BoxError;
+use reqwest::StatusCode;
+use std::io::ErrorKind;
+
+// the most of an error body shown in messages, the full body stays in `Http`
+const BODY_PREVIEW: usize = 200;
+
+/// Why an upload failed, as returned by [`Uploader`](crate::Uploader); backends return boxed
+/// errors, those that are one of these keep their variant on the way up.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading the file.
+    Io(std::io::Error),
+    /// The server answered with an error status.
+    Http { status: StatusCode, body: String },
+    /// The connection failed or broke: DNS, refused, reset, TLS.
+    Transport(BoxError),
+    /// No answer in time.
+    Timeout(BoxError),
+    /// Rejected credentials, 401 and 403 answers, unknown host keys.
+    Auth(String),
+    /// The server received something else than was sent.
+    ChecksumMismatch(String),
+    /// Options that can't work, e.g. a chunk larger than --max-memory.
+    Config(String),
+    /// Stopped by the shutdown token, aborted or left for the next run to resume.
+    Cancelled,
+    /// Anything else a backend reported.
+    Upload(BoxError),
+}
+
+impl Error {
+    /// Makes an error of an answer, 401 and 403 are authentication failures.
+    pub fn from_status(status: StatusCode, body: String) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Error::Auth(Error::Http { status, body }.to_string())
+            }
+            _ => Error::Http { status, body },
+        }
+    }
+
+    /// Whether sending the same request again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Http { status, .. } => {
+                status.is_server_error()
+                    || *status == StatusCode::REQUEST_TIMEOUT
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::Transport(_) | Error::Timeout(_) | Error::ChecksumMismatch(_) => true,
+            _ => false,
+        }
+    }
+
+    /// A short name of the variant, for summaries.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Io(_) => "io",
+            Error::Http { .. } => "http",
+            Error::Transport(_) => "transport",
+            Error::Timeout(_) => "timeout",
+            Error::Auth(_) => "auth",
+            Error::ChecksumMismatch(_) => "checksum",
+            Error::Config(_) => "config",
+            Error::Cancelled => "cancelled",
+            Error::Upload(_) => "other",
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Http { status, body } => {
+                write!(f, "Request failed with status: {}", status)?;
+                // one line of it, error pages are often whole HTML documents
+                let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+                match body.char_indices().nth(BODY_PREVIEW) {
+                    Some((end, _)) => write!(f, ": {}...", &body[..end]),
+                    None if !body.is_empty() => write!(f, ": {}", body),
+                    None => Ok(()),
+                }
+            }
+            Error::Transport(e) | Error::Timeout(e) => {
+                // reqwest keeps the reason, e.g. connection refused, in the
+                // sources, some of which repeat their own source
+                let mut message = e.to_string();
+                let mut source = e.source();
+                while let Some(e) = source {
+                    let reason = e.to_string();
+                    if !message.contains(&reason) {
+                        message = format!("{}: {}", message, reason);
+                    }
+                    source = e.source();
+                }
+                write!(f, "{}", message)
+            }
+            Error::Auth(message) | Error::ChecksumMismatch(message) | Error::Config(message) => {
+                write!(f, "{}", message)
+            }
+            Error::Cancelled => write!(f, "Upload cancelled"),
+            Error::Upload(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Transport(e) | Error::Timeout(e) | Error::Upload(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+// network failures of the socket based backends arrive as IO errors too
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        match error.kind() {
+            ErrorKind::TimedOut => Error::Timeout(error.into()),
+            ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof => Error::Transport(error.into()),
+            _ => Error::Io(error),
+        }
+    }
+}
+
+impl From<BoxError> for Error {
+    fn from(error: BoxError) -> Self {
+        let error = match error.downcast::<Error>() {
+            Ok(error) => return *error,
+            Err(error) => error,
+        };
+        let error = match error.downcast::<std::io::Error>() {
+            Ok(error) => return (*error).into(),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<reqwest::Error>() {
+            Ok(e) if e.is_timeout() => return Error::Timeout(e),
+            Ok(e) if e.is_connect() || e.is_request() || e.is_body() => return Error::Transport(e),
+            Ok(e) => match e.status() {
+                Some(status) => return Error::from_status(status, String::new()),
+                None => e as BoxError,
+            },
+            Err(error) => error,
+        };
+        if error.is::<tokio::time::error::Elapsed>() {
+            return Error::Timeout(error);
+        }
+        if error.is::<rustls::Error>() {
+            return Error::Transport(error);
+        }
+        Error::Upload(error)
+    }
+}
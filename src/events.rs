@@ -0,0 +1,84 @@
+use crate::error::Error;
+use crate::files::UploadFile;
+use crate::uploader::FileResult;
+
+/// What happens to the files of an [`Uploader`](crate::Uploader); for each file `Queued` (only in
+/// `upload_all`), `Started`, then progress, and `Finished`, `Failed` or `Skipped` last.
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// Waiting for a free job.
+    Queued {
+        file: &'a UploadFile,
+    },
+    /// The upload begins, before the backend is asked to start it.
+    Started {
+        file: &'a UploadFile,
+        /// The name the server sees.
+        name: &'a str,
+        /// Bytes on disk.
+        size: u64,
+        /// None when the file is streamed as one part.
+        chunk_size: Option<usize>,
+        total_chunks: u64,
+    },
+    /// The target kept `offset` bytes from an earlier attempt, they are not sent again.
+    Resumed {
+        file: &'a UploadFile,
+        offset: u64,
+    },
+    /// `sent` of `size` bytes on disk were handed to the backend so far, reported
+    /// every 100ms while a file is sent and once when it is done.
+    BytesSent {
+        file: &'a UploadFile,
+        sent: u64,
+        size: u64,
+    },
+    /// Chunk `index` (from 0) is on the target, `resumed` when it already was.
+    ChunkDone {
+        file: &'a UploadFile,
+        index: u64,
+        total: u64,
+        response: Option<&'a str>,
+        resumed: bool,
+    },
+    /// A failed chunk is sent again, `in_flight` is the window after backing off.
+    Retried {
+        file: &'a UploadFile,
+        index: u64,
+        attempt: u32,
+        error: &'a Error,
+        in_flight: usize,
+    },
+    /// Something the backend reported that doesn't fail the upload.
+    Warning {
+        file: &'a UploadFile,
+        message: &'a str,
+    },
+    /// The target keeps the failed upload of `name`, `location` when the next run
+    /// continues from there, otherwise running it again is enough.
+    Resumable {
+        file: &'a UploadFile,
+        name: &'a str,
+        location: Option<&'a str>,
+    },
+    /// The file is on the target.
+    Finished {
+        result: &'a FileResult,
+    },
+    /// The upload failed or was cancelled, `error` is also in the outcome of `result`.
+    Failed {
+        result: &'a FileResult,
+        error: &'a Error,
+    },
+    /// Not started after a shutdown.
+    Skipped {
+        file: &'a UploadFile,
+    },
+}
+
+/// Receives the events of an [`Uploader`](crate::Uploader), e.g. to drive a UI; called from the
+/// upload tasks, so implementations should return quickly.
+pub trait Observer: Send + Sync + std::fmt::Debug {
+    /// Called for every event of every file.
+    fn on_event(&self, event: &Event);
+}
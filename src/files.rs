@@ -0,0 +1,473 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
+use serde_json::{Map, Value};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// a file to upload and its place below the target, e.g. photos/2024/a.jpg
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadFile {
+    pub path: PathBuf,
+    pub relative_path: PathBuf,
+}
+
+impl UploadFile {
+    // a single file, uploaded by its name
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let relative_path = PathBuf::from(path.file_name().unwrap_or_default());
+        Self { path, relative_path }
+    }
+}
+
+// the files behind the given paths, folders are walked recursively and their
+// files keep their place below the folder; paths that can't be read are
+// passed to `on_error` and skipped
+pub fn collect<P: AsRef<Path>>(paths: &[P], mut on_error: impl FnMut(&Path, std::io::Error)) -> Vec<UploadFile> {
+    let mut files = vec![];
+    for path in paths {
+        match std::fs::canonicalize(path) {
+            Ok(full_path) => {
+                let relative = PathBuf::from(full_path.file_name().unwrap_or_default());
+                collect_path(full_path, relative, &mut files, &mut on_error);
+            }
+            Err(e) => on_error(path.as_ref(), e),
+        }
+    }
+    files
+}
+
+fn collect_path(
+    path: PathBuf,
+    relative: PathBuf,
+    files: &mut Vec<UploadFile>,
+    on_error: &mut impl FnMut(&Path, std::io::Error),
+) {
+    if path.is_dir() {
+        collect_dir(path, relative, files, on_error);
+    } else if path.is_file() {
+        files.push(UploadFile {
+            path,
+            relative_path: relative,
+        });
+    } else {
+        on_error(
+            &path,
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file or directory"),
+        );
+    }
+}
+
+fn collect_dir(
+    path: PathBuf,
+    relative: PathBuf,
+    files: &mut Vec<UploadFile>,
+    on_error: &mut impl FnMut(&Path, std::io::Error),
+) {
+    match std::fs::read_dir(&path) {
+        Ok(entries) => {
+            for entry in entries {
+                match entry {
+                    Ok(entry) => collect_path(entry.path(), relative.join(entry.file_name()), files, on_error),
+                    Err(e) => on_error(&path, e),
+                }
+            }
+        }
+        Err(e) => on_error(&path, e),
+    }
+}
+
+// why a file was left out by a `Filter`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reason {
+    TooSmall,
+    TooLarge,
+    // modified before the `newer_than` cutoff
+    TooOld,
+    // modified after the `older_than` cutoff
+    TooNew,
+    Type,
+}
+
+impl Reason {
+    // a short name for summaries
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Reason::TooSmall | Reason::TooLarge => "size",
+            Reason::TooOld | Reason::TooNew => "age",
+            Reason::Type => "type",
+        }
+    }
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reason::TooSmall => write!(f, "smaller than the minimum size"),
+            Reason::TooLarge => write!(f, "larger than the maximum size"),
+            Reason::TooOld => write!(f, "not modified since the cutoff"),
+            Reason::TooNew => write!(f, "modified after the cutoff"),
+            Reason::Type => write!(f, "not a selected file type"),
+        }
+    }
+}
+
+// a file a `Filter` left out
+#[derive(Debug, Clone)]
+pub struct Filtered {
+    pub file: UploadFile,
+    pub size: u64,
+    pub reason: Reason,
+}
+
+// selects the collected files by size, modification time and extension; all
+// given conditions have to hold
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    // modified after this time
+    pub newer_than: Option<SystemTime>,
+    // modified before this time
+    pub older_than: Option<SystemTime>,
+    // extensions without the dot, any case; empty selects all
+    pub extensions: Vec<String>,
+    pub exclude_extensions: Vec<String>,
+}
+
+impl Filter {
+    pub fn check(&self, path: &Path, metadata: &Metadata) -> Result<(), Reason> {
+        let size = metadata.len();
+        if self.min_size.is_some_and(|min_size| size < min_size) {
+            return Err(Reason::TooSmall);
+        }
+        if self.max_size.is_some_and(|max_size| size > max_size) {
+            return Err(Reason::TooLarge);
+        }
+
+        if self.newer_than.is_some() || self.older_than.is_some() {
+            // filesystems without modification times select everything
+            if let Ok(modified) = metadata.modified() {
+                if self.newer_than.is_some_and(|cutoff| modified <= cutoff) {
+                    return Err(Reason::TooOld);
+                }
+                if self.older_than.is_some_and(|cutoff| modified >= cutoff) {
+                    return Err(Reason::TooNew);
+                }
+            }
+        }
+
+        let extension = path.extension().map(|extension| extension.to_string_lossy());
+        let matches = |extensions: &[String]| {
+            extension
+                .as_ref()
+                .is_some_and(|extension| extensions.iter().any(|e| e.eq_ignore_ascii_case(extension)))
+        };
+        if !self.extensions.is_empty() && !matches(&self.extensions) {
+            return Err(Reason::Type);
+        }
+        if matches(&self.exclude_extensions) {
+            return Err(Reason::Type);
+        }
+        Ok(())
+    }
+
+    // the files to upload and those left out; files that can't be read are
+    // passed to `on_error` and dropped
+    pub fn apply(
+        &self,
+        files: Vec<UploadFile>,
+        mut on_error: impl FnMut(&Path, std::io::Error),
+    ) -> (Vec<UploadFile>, Vec<Filtered>) {
+        let mut selected = vec![];
+        let mut filtered = vec![];
+        for file in files {
+            match std::fs::metadata(&file.path) {
+                Ok(metadata) => match self.check(&file.path, &metadata) {
+                    Ok(()) => selected.push(file),
+                    Err(reason) => filtered.push(Filtered {
+                        file,
+                        size: metadata.len(),
+                        reason,
+                    }),
+                },
+                Err(e) => on_error(&file.path, e),
+            }
+        }
+        (selected, filtered)
+    }
+}
+
+// `90s`, `30m`, `24h`, `7d` or `2w`
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let seconds: u64 = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.parse::<u64>().ok()?.saturating_mul(seconds)))
+}
+
+// `24h`, `30m`, `7d` or `2w` ago, a local date `2024-05-01` or time
+// `2024-05-01T12:00:00`, or an RFC 3339 time with offset
+pub fn parse_time(s: &str) -> Result<SystemTime, String> {
+    let s = s.trim();
+    let invalid = || format!("Invalid time {}, expected e.g. 24h, 7d, 2024-05-01 or 2024-05-01T12:00:00", s);
+
+    if let Some(ago) = parse_duration(s) {
+        return Ok(SystemTime::now().checked_sub(ago).unwrap_or(UNIX_EPOCH));
+    }
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.into());
+    }
+    let local = match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(date) => date.and_hms_opt(0, 0, 0).ok_or_else(invalid)?,
+        Err(_) => NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")
+            .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S"))
+            .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M"))
+            .map_err(|_| invalid())?,
+    };
+    let local: DateTime<Local> = local.and_local_timezone(Local).earliest().ok_or_else(invalid)?;
+    Ok(local.into())
+}
+
+// when the uploads of `key`, e.g. a host and its paths, last ran without failures
+#[derive(Debug, Clone)]
+pub struct LastRun {
+    path: PathBuf,
+}
+
+impl LastRun {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn default_file() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("dup-cli").join("last-run.json"))
+    }
+
+    pub fn get(&self, key: &str) -> Option<SystemTime> {
+        let millis = self.read().get(key)?.as_u64()?;
+        Some(UNIX_EPOCH + Duration::from_millis(millis))
+    }
+
+    pub fn record(&self, key: &str, time: SystemTime) -> std::io::Result<()> {
+        let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let mut runs = self.read();
+        runs.insert(key.to_string(), Value::from(millis));
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, Value::Object(runs).to_string())
+    }
+
+    fn read(&self) -> Map<String, Value> {
+        std::fs::read(&self.path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // a folder of its own per test, removed on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("dup-cli-files-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        // a file of `size` bytes, modified at `modified`
+        fn file(&self, name: &str, size: usize, modified: SystemTime) -> PathBuf {
+            let path = self.0.join(name);
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).unwrap();
+            }
+            std::fs::write(&path, vec![0u8; size]).unwrap();
+            std::fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn check(filter: &Filter, path: &Path) -> Result<(), Reason> {
+        filter.check(path, &std::fs::metadata(path).unwrap())
+    }
+
+    fn local(s: &str) -> SystemTime {
+        let time = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap();
+        Local.from_local_datetime(&time).earliest().unwrap().into()
+    }
+
+    #[test]
+    fn sizes_are_inclusive() {
+        let dir = TempDir::new("sizes");
+        let now = SystemTime::now();
+        let filter = Filter {
+            min_size: Some(10),
+            max_size: Some(20),
+            ..Filter::default()
+        };
+        assert_eq!(check(&filter, &dir.file("9", 9, now)), Err(Reason::TooSmall));
+        assert_eq!(check(&filter, &dir.file("10", 10, now)), Ok(()));
+        assert_eq!(check(&filter, &dir.file("20", 20, now)), Ok(()));
+        assert_eq!(check(&filter, &dir.file("21", 21, now)), Err(Reason::TooLarge));
+        // empty files are only left out by a minimum
+        assert_eq!(check(&Filter::default(), &dir.file("0", 0, now)), Ok(()));
+    }
+
+    #[test]
+    fn cutoffs_are_exclusive() {
+        let dir = TempDir::new("cutoffs");
+        let cutoff = SystemTime::now() - DAY;
+        let before = dir.file("before", 1, cutoff - Duration::from_secs(1));
+        let at = dir.file("at", 1, cutoff);
+        let after = dir.file("after", 1, cutoff + Duration::from_secs(1));
+
+        let newer = Filter {
+            newer_than: Some(cutoff),
+            ..Filter::default()
+        };
+        assert_eq!(check(&newer, &before), Err(Reason::TooOld));
+        assert_eq!(check(&newer, &at), Err(Reason::TooOld));
+        assert_eq!(check(&newer, &after), Ok(()));
+
+        let older = Filter {
+            older_than: Some(cutoff),
+            ..Filter::default()
+        };
+        assert_eq!(check(&older, &before), Ok(()));
+        assert_eq!(check(&older, &at), Err(Reason::TooNew));
+        assert_eq!(check(&older, &after), Err(Reason::TooNew));
+
+        // a window between both
+        let between = Filter {
+            newer_than: Some(cutoff - DAY),
+            older_than: Some(cutoff),
+            ..Filter::default()
+        };
+        assert_eq!(check(&between, &before), Ok(()));
+        assert_eq!(check(&between, &dir.file("old", 1, cutoff - DAY * 2)), Err(Reason::TooOld));
+    }
+
+    #[test]
+    fn extensions_ignore_case() {
+        let dir = TempDir::new("extensions");
+        let now = SystemTime::now();
+        let filter = Filter {
+            extensions: vec!["jpg".to_string(), "PNG".to_string()],
+            exclude_extensions: vec!["png".to_string()],
+            ..Filter::default()
+        };
+        assert_eq!(check(&filter, &dir.file("a.JPG", 1, now)), Ok(()));
+        assert_eq!(check(&filter, &dir.file("a.png", 1, now)), Err(Reason::Type));
+        assert_eq!(check(&filter, &dir.file("a.txt", 1, now)), Err(Reason::Type));
+        assert_eq!(check(&filter, &dir.file("jpg", 1, now)), Err(Reason::Type));
+
+        let exclude = Filter {
+            exclude_extensions: vec!["tmp".to_string()],
+            ..Filter::default()
+        };
+        assert_eq!(check(&exclude, &dir.file("a.TMP", 1, now)), Err(Reason::Type));
+        assert_eq!(check(&exclude, &dir.file("tmp", 1, now)), Ok(()));
+    }
+
+    #[test]
+    fn apply_splits_the_files() {
+        let dir = TempDir::new("apply");
+        let now = SystemTime::now();
+        let files = vec![
+            UploadFile::new(dir.file("small", 1, now)),
+            UploadFile::new(dir.file("large", 100, now)),
+            UploadFile::new(dir.0.join("missing")),
+        ];
+        let filter = Filter {
+            min_size: Some(10),
+            ..Filter::default()
+        };
+        let mut errors = vec![];
+        let (selected, filtered) = filter.apply(files, |path, _| errors.push(path.to_path_buf()));
+        assert_eq!(selected, [UploadFile::new(dir.0.join("large"))]);
+        assert_eq!(filtered.len(), 1);
+        assert_eq!((filtered[0].size, filtered[0].reason), (1, Reason::TooSmall));
+        assert_eq!(errors, [dir.0.join("missing")]);
+    }
+
+    #[test]
+    fn collect_keeps_the_tree() {
+        let dir = TempDir::new("collect");
+        let now = SystemTime::now();
+        dir.file("photos/2024/a.jpg", 1, now);
+        dir.file("photos/b.jpg", 1, now);
+        let mut errors = 0;
+        let mut files = collect(&[dir.0.join("photos"), dir.0.join("missing")], |_, _| errors += 1);
+        files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+        let relative: Vec<_> = files.iter().map(|file| file.relative_path.clone()).collect();
+        assert_eq!(relative, [PathBuf::from("photos/2024/a.jpg"), PathBuf::from("photos/b.jpg")]);
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("24h"), Some(DAY));
+        assert_eq!(parse_duration(" 7d "), Some(DAY * 7));
+        assert_eq!(parse_duration("2w"), Some(DAY * 14));
+        assert_eq!(parse_duration("0m"), Some(Duration::ZERO));
+        for invalid in ["", "1", "h", "1.5h", "-1h", "1H", "1y", "1 h", "02:00"] {
+            assert_eq!(parse_duration(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn times_in_every_format() {
+        assert_eq!(parse_time("2024-05-01"), Ok(local("2024-05-01 00:00:00")));
+        assert_eq!(parse_time("2024-05-01T12:30:15"), Ok(local("2024-05-01 12:30:15")));
+        assert_eq!(parse_time("2024-05-01 12:30:15"), Ok(local("2024-05-01 12:30:15")));
+        assert_eq!(parse_time(" 2024-05-01 12:30 "), Ok(local("2024-05-01 12:30:00")));
+        let utc = chrono::Utc.with_ymd_and_hms(2024, 5, 1, 10, 0, 0).unwrap();
+        assert_eq!(parse_time("2024-05-01T12:00:00+02:00"), Ok(utc.into()));
+        assert_eq!(parse_time("2024-05-01T10:00:00Z"), Ok(utc.into()));
+    }
+
+    #[test]
+    fn times_ago() {
+        let before = SystemTime::now();
+        let ago = parse_time("24h").unwrap();
+        let after = SystemTime::now();
+        assert!(ago >= before - DAY && ago <= after - DAY);
+        // too far back for the clock
+        assert_eq!(parse_time("18446744073709551615s"), Ok(UNIX_EPOCH));
+    }
+
+    #[test]
+    fn invalid_times() {
+        for invalid in ["", "yesterday", "2024-13-01", "2024-05-32", "12:00", "2024/05/01", "05-01-2024", "1.5d"] {
+            let error = parse_time(invalid).unwrap_err();
+            assert!(error.starts_with("Invalid time"), "{}: {}", invalid, error);
+        }
+    }
+}
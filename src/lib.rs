@@ -0,0 +1,24 @@
+//! Uploads files to HTTP servers, S3, tus, WebDAV, SFTP and FTP targets, in
+//! parallel, chunked and optionally encrypted; the library behind `dup-cli`.
+//!
+//! Build a [`backend::Backend`] with [`backend::build`], drive files through it
+//! with an [`Uploader`] and follow them with an [`Observer`].
+
+pub mod backend;
+pub mod client;
+pub mod crypto;
+pub mod error;
+pub mod events;
+pub mod files;
+pub mod pool;
+pub mod stats;
+pub mod tls;
+pub mod tuning;
+pub mod units;
+pub mod uploader;
+
+pub use error::Error;
+pub use events::{Event, Observer};
+pub use files::UploadFile;
+pub use stats::Stats;
+pub use uploader::{ChunkCounts, FileResult, Outcome, UploadReport, Uploader, UploaderBuilder};
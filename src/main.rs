@@ -1,283 +1,329 @@
-use crate::cli::Cli;
-use crate::uploader::Uploader;
-
-pub mod cli;
-pub mod uploader;
+use crate::cli::{Cli, Command, UploadArgs};
+use dup_cli::backend::{self, BoxError, HttpTarget};
+use dup_cli::crypto::{self, ENCRYPTED_SUFFIX};
+use dup_cli::tuning::ChunkSize;
+use dup_cli::units::Units;
+use crate::ui::{Console, JsonLines};
+use dup_cli::files::LastRun;
+use dup_cli::{files, Error, Observer, Outcome, Stats, Uploader};
+use serde_json::json;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_util::sync::CancellationToken;
+
+mod cli;
+mod schedule;
+mod ui;
+mod watch;
 
 #[tokio::main]
 async fn main() {
-    let mut args = Cli::from_args();
-
-    if let Err(err) = args.validate() {
-        eprintln!("Error: {}", err);
-        ::std::process::exit(1);
+    let cli = Cli::from_args();
+    let mut args = cli.upload;
+
+    match cli.command {
+        Some(Command::Watch(watch)) => {
+            let mut watch = *watch;
+            if watch.upload.paths.is_empty() {
+                eprintln!("Error: watch needs at least one folder or file to watch");
+                ::std::process::exit(EXIT_CONFIG);
+            }
+            validate(&mut watch.upload);
+            if watch.upload.dry_run {
+                eprintln!("Error: --dry-run can't be used with watch");
+                ::std::process::exit(EXIT_CONFIG);
+            }
+            let shutdown = CancellationToken::new();
+            tokio::spawn(handle_ctrl_c(shutdown.clone()));
+            let uploader = build_uploader(&watch.upload, &shutdown);
+            let code = watch::run(&watch, uploader, shutdown).await;
+            ::std::process::exit(code);
+        }
+        Some(Command::Schedule { action }) => {
+            let shutdown = CancellationToken::new();
+            tokio::spawn(handle_ctrl_c(shutdown.clone()));
+            ::std::process::exit(schedule::command(action, shutdown).await);
+        }
+        Some(command) => {
+            if let Err(err) = run_command(command, args.units) {
+                eprintln!("Error: {}", err);
+                ::std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
     }
 
-    let mut paths = vec![];
-    // print full path of each file
-    for path in args.paths {
-        match std::fs::canonicalize(&path) {
-            Ok(full_path) => {
-                handle_path(full_path, &mut paths);
+    // taken from UPLOAD_URL and stdin by validate, a queued upload keeps them
+    let host_given = !args.host.is_empty();
+    let paths_given = !args.paths.is_empty();
+    validate(&mut args);
+    if cli.at.is_some() || cli.every.is_some() {
+        match schedule::job_args(&args, host_given, paths_given) {
+            Ok(job_args) => ::std::process::exit(schedule::add(job_args, cli.at, cli.every)),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                ::std::process::exit(EXIT_CONFIG);
             }
-            Err(e) => eprintln!("Error: {}", e),
         }
     }
 
-    // create a channel to sum total file size
-    let (tx, rx) = std::sync::mpsc::channel();
+    let shutdown = CancellationToken::new();
+    tokio::spawn(handle_ctrl_c(shutdown.clone()));
 
-    let mut handles = vec![];
+    // files modified while this run reads them are picked up by the next one
+    let run_started = SystemTime::now();
+    let last_run_key = args.last_run_key();
 
-    let total_time = std::time::Instant::now();
+    let mut unreadable = 0;
+    let mut on_error = |path: &Path, e: std::io::Error| {
+        eprintln!("Error: {}: {}", path.display(), e);
+        unreadable += 1;
+    };
+    let paths = files::collect(&std::mem::take(&mut args.paths), &mut on_error);
+    let (paths, filtered) = args.filter.apply(paths, &mut on_error);
 
-    if args.progress {
-        for path in paths {
-            let url_clone = args.host.clone();
-            let category = args.category.clone();
-            let tx_clone = tx.clone();
-            let _ = handle_upload_file_with_progress(path, &url_clone, category, tx_clone).await;
-        }
-    } else if let Some(chunk_size) = args.chunk_size {
-        for path in paths {
-            let url_clone = args.host.clone();
-            let tx_clone = tx.clone();
-            let handle = std::thread::spawn(move || {
-                let runtime = tokio::runtime::Runtime::new().unwrap();
+    if args.dry_run {
+        ui::print_dry_run(&paths, &filtered, args.json, args.units);
+        ::std::process::exit(if unreadable > 0 { EXIT_IO } else { 0 });
+    }
 
-                runtime.block_on(async {
-                    let _ = handle_upload_file_with_chunk_size(path, &url_clone, tx_clone, chunk_size).await;
-                });
-            });
-            handles.push(handle);
-        }
+    let uploader = build_uploader(&args, &shutdown);
 
-        for handle in handles {
-            handle.join().unwrap();
-        }
+    let total_time = std::time::Instant::now();
+    let total_files = paths.len();
+
+    // one file at a time with progress bars, those of parallel uploads would interleave
+    let jobs = if args.progress { 1 } else { args.jobs };
+    let results = uploader.upload_all(paths, jobs).await;
+
+    let uploaded = results
+        .iter()
+        .filter(|result| matches!(result.outcome, Outcome::Uploaded(_)))
+        .count();
+    let not_started = results
+        .iter()
+        .filter(|result| matches!(result.outcome, Outcome::NotStarted))
+        .count();
+    // why files failed, e.g. `http: 2, timeout: 1`
+    let errors: Vec<&Error> = results
+        .iter()
+        .filter_map(|result| match &result.outcome {
+            Outcome::Failed(e) if !matches!(e, Error::Cancelled) => Some(e),
+            _ => None,
+        })
+        .collect();
+    let kinds = ui::tally(std::iter::repeat_n("unreadable", unreadable).chain(errors.iter().map(|e| e.kind())));
+    let filtered = ui::tally(filtered.iter().map(|filtered| filtered.reason.kind()));
+
+    let stats = Stats::new(&results, total_time.elapsed());
+
+    if args.json {
+        JsonLines::print(json!({
+            "event": "summary",
+            "files": total_files + unreadable,
+            "uploaded": uploaded,
+            "failed": errors.len() + unreadable,
+            "not_started": not_started,
+            "interrupted": shutdown.is_cancelled(),
+            "errors": ui::tally_json(&kinds),
+            "filtered": ui::tally_json(&filtered),
+            "stats": ui::stats_json(&stats),
+        }));
     } else {
-        for path in paths {
-            let url_clone = args.host.clone();
-            let category = args.category.clone();
-            let tx_clone = tx.clone();
-            let handle = std::thread::spawn(move || {
-                let runtime = tokio::runtime::Runtime::new().unwrap();
-
-                runtime.block_on(async {
-                    let _ = handle_upload_file(path, &url_clone, category, tx_clone).await;
-                });
-            });
-            handles.push(handle);
+        if shutdown.is_cancelled() {
+            println!(
+                "Interrupted: {} of {} files uploaded, {} not started",
+                uploaded, total_files, not_started
+            );
         }
-
-        for handle in handles {
-            handle.join().unwrap();
+        if !kinds.is_empty() {
+            println!(
+                "Failed: {} of {} files ({})",
+                errors.len() + unreadable,
+                total_files + unreadable,
+                ui::tally_text(&kinds)
+            );
+        }
+        if !filtered.is_empty() {
+            let files: usize = filtered.iter().map(|(_, count)| count).sum();
+            println!("Filtered: {} files ({})", files, ui::tally_text(&filtered));
         }
+        ui::print_stats(&stats, args.units);
     }
 
-    let total_time = total_time.elapsed().as_secs();
-
-    println!("Total time: {}s", total_time);
-
-    let mut total_size = 0;
-    // drain the channel to sum total file size
-    while let Ok(size) = rx.try_recv() {
-        total_size += size;
+    if shutdown.is_cancelled() {
+        ::std::process::exit(EXIT_INTERRUPTED);
     }
 
-    println!("Total size: {}", file_size_human_readable(total_size));
-
-    // the average speed of the upload
-    let average_speed = total_size as f64 / total_time as f64;
-    println!(
-        "Average speed: {}/s",
-        file_size_human_readable(average_speed as u64)
-    );
-}
-
-fn handle_path(path: std::path::PathBuf, paths: &mut Vec<std::path::PathBuf>) {
-    if path.is_dir() {
-        handle_dir(path, paths);
-    } else if path.is_file() {
-        paths.push(path.clone());
-    } else {
-        println!("{} is not a file or directory", path.display());
+    // failed files are tried again by the next run
+    if args.modified_since_last_run && errors.is_empty() && unreadable == 0 {
+        if let Some(last_run) = LastRun::default_file().map(LastRun::new) {
+            if let Err(e) = last_run.record(&last_run_key, run_started) {
+                eprintln!("Warning: failed to record the time of this run: {}", e);
+            }
+        }
     }
-}
 
-fn file_size_human_readable(file_size: u64) -> String {
-    if file_size <= 1024 {
-        format!("{}B", file_size)
-    } else if file_size <= 1024 * 1024 {
-        format!("{:.2}KB", file_size as f64 / 1024.0)
-    } else if file_size <= 1024 * 1024 * 1024 {
-        format!("{:.2}MB", file_size as f64 / 1024.0 / 1024.0)
-    } else {
-        format!("{:.2}GB", file_size as f64 / 1024.0 / 1024.0 / 1024.0)
+    // the status of the kind of failure, or 1 when files failed for different reasons
+    let mut codes: Vec<i32> = errors.iter().map(|error| exit_code(error)).collect();
+    if unreadable > 0 {
+        codes.push(EXIT_IO);
+    }
+    codes.sort_unstable();
+    codes.dedup();
+    match codes[..] {
+        [] => {}
+        [code] => ::std::process::exit(code),
+        _ => ::std::process::exit(1),
     }
 }
 
-async fn handle_upload_file(
-    path: std::path::PathBuf,
-    url: &str,
-    kind_of_upload: cli::KindOfUpload,
-    tx: std::sync::mpsc::Sender<u64>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let time = std::time::Instant::now();
-    let mut uploader = Uploader::new(url);
-    let file_size = std::fs::metadata(&path)?.len();
-    tx.send(file_size).unwrap();
-    let file_size = file_size_human_readable(file_size);
-
-    println!("Starting upload of {} [{}]", path.display(), file_size);
+// exits on options that can't work
+fn validate(args: &mut UploadArgs) {
+    if let Err(err) = args.validate() {
+        eprintln!("Error: {}", err);
+        ::std::process::exit(EXIT_CONFIG);
+    }
 
-    if kind_of_upload == cli::KindOfUpload::Binary {
-        match uploader.add_header(
-            "Content-Type".to_string(),
-            "application/octet-stream".to_string(),
-        ) {
-            Ok(_) => {}
-            Err(e) => eprintln!("Error: {}", e),
-        }
-        match uploader.add_header(
-            "X-Filename".to_string(),
-            path.file_name().unwrap().to_str().unwrap().to_string(),
-        ) {
-            Ok(_) => {}
-            Err(e) => eprintln!("Error: {}", e),
-        }
+    if args.insecure {
+        eprintln!("**************************************************************************");
+        eprintln!("WARNING: --insecure disables TLS certificate and hostname verification.");
+        eprintln!("Anyone on the network path can impersonate the server and read your files.");
+        eprintln!("**************************************************************************");
     }
+}
 
-    match uploader.upload_file(&path).await {
-        Ok(res) => {
-            let download_link = match res.text().await {
-                Ok(text) => text,
-                Err(_) => "Error".to_string(),
-            };
-            println!(
-                "[{}s][{}][{}] - Download: {}",
-                time.elapsed().as_secs(),
-                path.display(),
-                file_size,
-                download_link
-            );
-            // check if res is json, text print it
+fn build_uploader(args: &UploadArgs, shutdown: &CancellationToken) -> Uploader {
+    // one uploader, and so one connection pool, for every file
+    let client = match args.client.build() {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ::std::process::exit(EXIT_CONFIG);
         }
-        Err(e) => {
-            eprintln!("[{}s] Error: {}", time.elapsed().as_secs(), e);
-            return Err(e);
+    };
+    let backend = match backend::build(
+        &args.backend_kind(),
+        HttpTarget::new(client, &args.host),
+        &args.backend_options,
+    ) {
+        Ok(backend) => backend,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            ::std::process::exit(EXIT_CONFIG);
         }
     };
-
-    Ok(())
+    let observer: Arc<dyn Observer> = match args.json {
+        true => Arc::new(JsonLines::default()),
+        false => Arc::new(Console::new(args.progress, args.units)),
+    };
+    let mut builder = Uploader::builder(backend).observer(observer).shutdown(shutdown.clone());
+    if let Some(concurrency) = args.chunk_concurrency {
+        builder = builder.chunk_concurrency(concurrency);
+    }
+    if let Some(max_memory) = args.max_memory {
+        builder = builder.max_memory(max_memory);
+    }
+    if args.chunk_size == Some(ChunkSize::Auto) {
+        builder = builder.auto_chunk_size(args.host.clone());
+    }
+    if let Some(key_source) = args.key_source.clone() {
+        builder = builder.key_source(key_source);
+    }
+    builder.build()
 }
 
-async fn handle_upload_file_with_progress(
-    path: std::path::PathBuf,
-    url: &str,
-    kind_of_upload: cli::KindOfUpload,
-    tx: std::sync::mpsc::Sender<u64>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let time = std::time::Instant::now();
-    let mut uploader = Uploader::new(url);
-    let file_size = std::fs::metadata(&path)?.len();
-    tx.send(file_size).unwrap();
-    let file_size = file_size_human_readable(file_size);
-
-    println!("Starting upload of {} [{}]", path.display(), file_size);
-
-    if kind_of_upload == cli::KindOfUpload::Binary {
-        match uploader.add_header(
-            "Content-Type".to_string(),
-            "application/octet-stream".to_string(),
-        ) {
-            Ok(_) => {}
-            Err(e) => eprintln!("Error: {}", e),
-        }
-        match uploader.add_header(
-            "X-Filename".to_string(),
-            path.file_name().unwrap().to_str().unwrap().to_string(),
-        ) {
-            Ok(_) => {}
-            Err(e) => eprintln!("Error: {}", e),
-        }
+const EXIT_CONFIG: i32 = 2;
+const EXIT_IO: i32 = 3;
+const EXIT_INTERRUPTED: i32 = 130;
+
+// exit status by why uploads failed
+fn exit_code(error: &Error) -> i32 {
+    match error {
+        Error::Upload(_) => 1,
+        Error::Config(_) => EXIT_CONFIG,
+        Error::Io(_) => EXIT_IO,
+        Error::Http { .. } => 4,
+        Error::Transport(_) => 5,
+        Error::Timeout(_) => 6,
+        Error::Auth(_) => 7,
+        Error::ChecksumMismatch(_) => 8,
+        Error::Cancelled => EXIT_INTERRUPTED,
     }
-
-    match uploader.upload_file_with_progress(&path).await {
-        Ok(res) => {
-            let download_link = match res.text().await {
-                Ok(text) => text,
-                Err(_) => "Error".to_string(),
-            };
-            println!(
-                "[{}s][{}][{}] - Download: {}",
-                time.elapsed().as_secs(),
-                path.display(),
-                file_size,
-                download_link
-            );
-            // check if res is json, text print it
-        }
-        Err(e) => {
-            eprintln!("[{}s] Error: {}", time.elapsed().as_secs(), e);
-            return Err(e);
-        }
-    };
-
-    Ok(())
 }
 
-async fn handle_upload_file_with_chunk_size(
-    path: std::path::PathBuf,
-    url: &str,
-    tx: std::sync::mpsc::Sender<u64>,
-    chunk_size: usize,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let time = std::time::Instant::now();
-    let uploader = Uploader::new(url);
-    let file_size = std::fs::metadata(&path)?.len();
-    tx.send(file_size).unwrap();
-    let file_size = file_size_human_readable(file_size);
-
-    println!("Starting upload of {} [{}]", path.display(), file_size);
-
-    match uploader.upload_file_with_chunk_size(&path, chunk_size).await {
-        Ok(_) => {
-            // let download_link = match res.text().await {
-            //     Ok(text) => text,
-            //     Err(_) => "Error".to_string(),
-            // };
-            println!(
-                "[{}s][{}][{}]",
-                time.elapsed().as_secs(),
-                path.display(),
-                file_size
-            );
-            // check if res is json, text print it
-        }
-        Err(e) => {
-            eprintln!("[{}s] Error: {}", time.elapsed().as_secs(), e);
-            return Err(e);
-        }
-    };
+// the first Ctrl-C lets running uploads wind down, the second one quits
+async fn handle_ctrl_c(shutdown: CancellationToken) {
+    if tokio::signal::ctrl_c().await.is_err() {
+        return;
+    }
+    eprintln!("Interrupted, stopping after the running uploads, press Ctrl-C again to quit now");
+    shutdown.cancel();
 
-    Ok(())
+    if tokio::signal::ctrl_c().await.is_ok() {
+        eprintln!("Interrupted again, quitting");
+        ::std::process::exit(EXIT_INTERRUPTED);
+    }
 }
 
-fn handle_dir(path: std::path::PathBuf, paths: &mut Vec<std::path::PathBuf>) {
-    println!("{} is a directory", path.display());
-    match std::fs::read_dir(&path) {
-        Ok(entries) => {
-            for entry in entries {
-                match entry {
-                    Ok(entry) => {
-                        let path = entry.path();
-                        handle_path(path, paths);
+fn run_command(command: Command, units: Units) -> Result<(), BoxError> {
+    match command {
+        Command::Decrypt {
+            input,
+            output,
+            key_file,
+            passphrase,
+            identity,
+        } => {
+            let key = Command::decryption_key(key_file, passphrase, identity)?;
+            let output = match output {
+                Some(output) => output,
+                None => {
+                    let name = input.to_string_lossy();
+                    match name.strip_suffix(ENCRYPTED_SUFFIX) {
+                        Some(stripped) if !stripped.is_empty() => std::path::PathBuf::from(stripped),
+                        _ => std::path::PathBuf::from(format!("{}.dec", name)),
                     }
-                    Err(e) => eprintln!("Error: {}", e),
                 }
+            };
+            if output.exists() {
+                return Err(format!("{} already exists", output.display()).into());
+            }
+
+            let time = std::time::Instant::now();
+            match crypto::decrypt_file(&input, &output, &key) {
+                Ok(size) => {
+                    println!(
+                        "[{}][{}][{}] - Decrypted to {}",
+                        ui::seconds(time.elapsed()),
+                        input.display(),
+                        units.size(size),
+                        output.display()
+                    );
+                    Ok(())
+                }
+                Err(e) => {
+                    // never leave partially decrypted plaintext behind
+                    let _ = std::fs::remove_file(&output);
+                    Err(e)
+                }
+            }
+        }
+        // run until Ctrl-C, see main
+        Command::Watch(_) | Command::Schedule { .. } => unreachable!(),
+        Command::Keygen { key_file, identity } => {
+            if let Some(path) = key_file {
+                crypto::generate_key_file(&path)?;
+                println!("Key written to {}", path.display());
+            }
+            if let Some(path) = identity {
+                let public = crypto::generate_identity(&path)?;
+                println!("Identity written to {}", path.display());
+                println!("Public key (use with --recipient): {}", public);
             }
+            Ok(())
         }
-        Err(e) => eprintln!("Error: {}", e),
     }
 }
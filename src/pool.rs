@@ -0,0 +1,100 @@
+use crate::backend::BoxError;
+use crate::error::Error;
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// --max-memory, shared by the buffer pools of all files
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    size: usize,
+    permits: Arc<Semaphore>,
+}
+
+impl MemoryBudget {
+    pub fn new(size: u64) -> Self {
+        let size = usize::try_from(size).unwrap_or(usize::MAX).min(Semaphore::MAX_PERMITS);
+        Self {
+            size,
+            permits: Arc::new(Semaphore::new(size)),
+        }
+    }
+}
+
+// the chunk buffers of one file, handed from chunk to chunk instead of
+// allocated for each; with a budget, all pools together stay within
+// --max-memory and a file waits for a buffer to come back when it is spent
+#[derive(Debug)]
+pub struct BufferPool {
+    capacity: usize,
+    budget: Option<MemoryBudget>,
+    // what the allocated buffers took from the budget, returned with the pool
+    permits: Vec<OwnedSemaphorePermit>,
+    returned_tx: UnboundedSender<Vec<u8>>,
+    returned: UnboundedReceiver<Vec<u8>>,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize, budget: Option<MemoryBudget>) -> Result<Self, BoxError> {
+        if let Some(budget) = &budget {
+            if capacity > budget.size || u32::try_from(capacity).is_err() {
+                return Err(Error::Config(format!(
+                    "--max-memory {} is smaller than one chunk of {} bytes",
+                    budget.size, capacity
+                ))
+                .into());
+            }
+        }
+
+        let (returned_tx, returned) = unbounded_channel();
+        Ok(Self {
+            capacity,
+            budget,
+            permits: Vec::new(),
+            returned_tx,
+            returned,
+        })
+    }
+
+    // a returned buffer, or a new one when the budget allows
+    pub async fn get(&mut self) -> Result<Vec<u8>, BoxError> {
+        if let Ok(buffer) = self.returned.try_recv() {
+            return Ok(buffer);
+        }
+        let Some(budget) = &self.budget else {
+            return Ok(Vec::with_capacity(self.capacity));
+        };
+
+        tokio::select! {
+            Some(buffer) = self.returned.recv() => Ok(buffer),
+            permit = budget.permits.clone().acquire_many_owned(self.capacity as u32) => {
+                self.permits.push(permit?);
+                Ok(Vec::with_capacity(self.capacity))
+            }
+        }
+    }
+
+    pub fn recycler(&self) -> Recycler {
+        Recycler {
+            returned: self.returned_tx.clone(),
+        }
+    }
+}
+
+// gives sent chunks back to their pool
+#[derive(Debug, Clone)]
+pub struct Recycler {
+    returned: UnboundedSender<Vec<u8>>,
+}
+
+impl Recycler {
+    // `data` is the last handle on the chunk, unless the backend kept one, in
+    // which case its memory is copied once
+    pub fn recycle(&self, data: Bytes) {
+        let mut buffer = Vec::from(data);
+        buffer.clear();
+        let _ = self.returned.send(buffer);
+    }
+}
+
@@ -0,0 +1,610 @@
+use crate::cli::{ScheduleAction, UploadArgs};
+use crate::{EXIT_CONFIG, EXIT_INTERRUPTED, EXIT_IO};
+use chrono::{DateTime, Local, SecondsFormat};
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+// how often the spool is read again for uploads queued while the runner waits
+const POLL: Duration = Duration::from_secs(30);
+// a failed run is tried again after this, as often as MAX_ATTEMPTS allows
+const RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+const MAX_ATTEMPTS: u32 = 3;
+
+// an upload queued with --at or --every, run as `dup-cli <args>` in `dir`
+struct Job {
+    id: String,
+    args: Vec<String>,
+    dir: PathBuf,
+    next_run: DateTime<Local>,
+    // the run of the interval that next_run belongs to, later than it is a retry
+    due: DateTime<Local>,
+    every: Option<Duration>,
+    // failed runs since the last one that succeeded
+    attempts: u32,
+}
+
+impl Job {
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "args": self.args,
+            "dir": self.dir,
+            "next_run": self.next_run.to_rfc3339_opts(SecondsFormat::Secs, false),
+            "due": self.due.to_rfc3339_opts(SecondsFormat::Secs, false),
+            "every": self.every.map(|every| every.as_secs()),
+            "attempts": self.attempts,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let next_run = DateTime::parse_from_rfc3339(value.get("next_run")?.as_str()?).ok()?.into();
+        Some(Self {
+            id: value.get("id")?.as_str().filter(|id| valid_id(id))?.to_string(),
+            args: value
+                .get("args")?
+                .as_array()?
+                .iter()
+                .map(|arg| arg.as_str().map(str::to_string))
+                .collect::<Option<_>>()?,
+            dir: PathBuf::from(value.get("dir")?.as_str()?),
+            next_run,
+            due: match value.get("due").and_then(Value::as_str) {
+                Some(due) => DateTime::parse_from_rfc3339(due).ok()?.into(),
+                None => next_run,
+            },
+            every: value.get("every").and_then(Value::as_u64).map(Duration::from_secs),
+            attempts: value.get("attempts").and_then(Value::as_u64).unwrap_or(0) as u32,
+        })
+    }
+
+    fn command_line(&self) -> String {
+        std::iter::once("dup-cli".to_string()).chain(self.args.iter().cloned()).collect::<Vec<_>>().join(" ")
+    }
+
+    // the first run of the interval after now, the ones missed in between are not made up
+    fn next_interval(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let every = chrono::Duration::from_std(self.every?).ok()?;
+        let mut next = self.due + every;
+        while next <= now {
+            next += every;
+        }
+        Some(next)
+    }
+
+    // moves the job on after a run that ended with `failure` or succeeded, returns the
+    // result for the log and whether the job stays queued
+    fn reschedule(&mut self, failure: Option<String>, now: DateTime<Local>) -> (String, bool) {
+        // from the run of the interval, not from a retry, so retries don't move the schedule
+        let next = self.next_interval(now);
+        let retry = now + chrono::Duration::from_std(RETRY_DELAY).unwrap_or_default();
+        let result = match failure {
+            None => {
+                self.attempts = 0;
+                "succeeded".to_string()
+            }
+            Some(failure) => {
+                self.attempts += 1;
+                match next {
+                    // the next run of the interval comes first and starts over
+                    Some(next) if next <= retry => {
+                        self.attempts = 0;
+                        format!("{}, next run at {}", failure, time(next))
+                    }
+                    _ if self.attempts < MAX_ATTEMPTS => format!("{}, trying again at {}", failure, time(retry)),
+                    _ => {
+                        self.attempts = 0;
+                        format!("{}, giving up after {} attempts", failure, MAX_ATTEMPTS)
+                    }
+                }
+            }
+        };
+        match next {
+            _ if self.attempts > 0 => self.next_run = retry,
+            Some(next) => {
+                self.next_run = next;
+                self.due = next;
+            }
+            None => return (result, false),
+        }
+        (result, true)
+    }
+}
+
+// the folder of queued uploads, one JSON file each, and the log of their runs
+struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    // `DUP_SPOOL`, or ~/.local/share/dup-cli/spool
+    fn open() -> Result<Self, String> {
+        let dir = match std::env::var_os("DUP_SPOOL") {
+            Some(dir) => PathBuf::from(dir),
+            None => dirs::data_local_dir()
+                .map(|dir| dir.join("dup-cli").join("spool"))
+                .ok_or("No folder for queued uploads, set DUP_SPOOL")?,
+        };
+        std::fs::create_dir_all(&dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))
+                .map_err(|e| format!("{}: {}", dir.display(), e))?;
+        }
+        Ok(Self { dir })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    // in the order they run
+    fn jobs(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = std::fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().is_some_and(|extension| extension == "json"))
+            .filter_map(|entry| std::fs::read(entry.path()).ok())
+            .filter_map(|data| serde_json::from_slice(&data).ok())
+            .filter_map(|value| Job::from_json(&value))
+            .collect();
+        jobs.sort_by_key(|job| job.next_run);
+        jobs
+    }
+
+    // a new id from the time it was queued, e.g. 20240501-231500
+    fn new_id(&self) -> String {
+        let id = Local::now().format("%Y%m%d-%H%M%S").to_string();
+        let mut n = 1;
+        let mut unique = id.clone();
+        while self.path(&unique).exists() {
+            n += 1;
+            unique = format!("{}-{}", id, n);
+        }
+        unique
+    }
+
+    // written aside and renamed, so a runner never reads half a job
+    fn save(&self, job: &Job) -> std::io::Result<()> {
+        let temp = self.dir.join(format!(".{}.json.tmp", job.id));
+        private_file()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)?
+            .write_all(job.to_json().to_string().as_bytes())?;
+        std::fs::rename(&temp, self.path(&job.id))
+    }
+
+    fn remove(&self, id: &str) -> std::io::Result<()> {
+        std::fs::remove_file(self.path(id))
+    }
+
+    // printed and appended to schedule.log
+    fn log(&self, line: &str) {
+        println!("{}", line);
+        let path = self.dir.join("schedule.log");
+        let written = private_file()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut log| writeln!(log, "{}", line));
+        if let Err(e) = written {
+            eprintln!("Warning: failed to write {}: {}", path.display(), e);
+        }
+    }
+}
+
+// ids name files in the spool, `schedule remove ../x` must not reach outside it
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && !id.contains(['/', '\\']) && !id.contains("..")
+}
+
+// e.g. `1h`, `90m` or `45s`, as --every takes it
+fn interval(every: Duration) -> String {
+    let seconds = every.as_secs();
+    for (unit, length) in [("w", 7 * 24 * 60 * 60), ("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60)] {
+        if seconds.is_multiple_of(length) {
+            return format!("{}{}", seconds / length, unit);
+        }
+    }
+    format!("{}s", seconds)
+}
+
+fn time(time: DateTime<Local>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+// the command line without --at and --every, with the host and paths that came from
+// UPLOAD_URL or stdin, so the job uploads the same files to the same host; other
+// environment variables, e.g. DUP_PASSWORD, are those of the runner. Secrets given
+// as options are refused, they would be stored in the spool and its log
+pub fn job_args(upload: &UploadArgs, host_given: bool, paths_given: bool) -> Result<Vec<String>, String> {
+    let secrets = [
+        ("--passphrase", upload.passphrase.as_deref(), "DUP_PASSPHRASE"),
+        ("--password", upload.password.as_deref(), "DUP_PASSWORD"),
+        ("--secret-key", upload.secret_key.as_deref(), "AWS_SECRET_ACCESS_KEY"),
+        ("--session-token", upload.session_token.as_deref(), "AWS_SESSION_TOKEN"),
+        ("--ssh-key-passphrase", upload.ssh_key_passphrase.as_deref(), "DUP_SSH_KEY_PASSPHRASE"),
+    ];
+    for (option, value, variable) in secrets {
+        // the same as the variable when it came from there
+        if value.is_some_and(|value| std::env::var(variable).ok().as_deref() != Some(value)) {
+            return Err(format!(
+                "{} would be stored with the queued upload, export {} for `dup-cli schedule run` instead",
+                option, variable
+            ));
+        }
+    }
+    if upload.user.as_deref().is_some_and(|user| user.contains(':')) {
+        return Err(
+            "The password in --user would be stored with the queued upload, give only the user and export \
+             DUP_PASSWORD for `dup-cli schedule run` instead"
+                .into(),
+        );
+    }
+
+    let mut args = without_schedule(std::env::args_os().skip(1).map(|arg| arg.to_string_lossy().into_owned()));
+    // in front, paths may follow a `--`
+    if !host_given && !upload.host.is_empty() {
+        args.splice(0..0, ["--host".to_string(), upload.host.clone()]);
+    }
+    if !paths_given {
+        args.extend(upload.paths.iter().map(|path| path.display().to_string()));
+    }
+    Ok(args)
+}
+
+// the arguments without --at and --every and their values
+fn without_schedule(mut command_line: impl Iterator<Item = String>) -> Vec<String> {
+    let mut args = vec![];
+    while let Some(arg) = command_line.next() {
+        match arg.as_str() {
+            "--at" | "--every" => {
+                command_line.next();
+            }
+            _ if arg.starts_with("--at=") || arg.starts_with("--every=") => {}
+            _ => args.push(arg),
+        }
+    }
+    args
+}
+
+// readable by the owner only, jobs and the log hold command lines and paths
+fn private_file() -> std::fs::OpenOptions {
+    let mut options = std::fs::OpenOptions::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+}
+
+// queues `args` to run in the current folder at `at`, or right away, and every `every` after
+pub fn add(args: Vec<String>, at: Option<DateTime<Local>>, every: Option<Duration>) -> i32 {
+    let spool = match Spool::open() {
+        Ok(spool) => spool,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return EXIT_IO;
+        }
+    };
+    let dir = match std::env::current_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return EXIT_IO;
+        }
+    };
+    let next_run = at.unwrap_or_else(Local::now);
+    let job = Job {
+        id: spool.new_id(),
+        args,
+        dir,
+        next_run,
+        due: next_run,
+        every,
+        attempts: 0,
+    };
+    if let Err(e) = spool.save(&job) {
+        eprintln!("Error: failed to queue the upload in {}: {}", spool.dir.display(), e);
+        return EXIT_IO;
+    }
+
+    let every = match job.every {
+        Some(every) => format!(", then every {}", interval(every)),
+        None => String::new(),
+    };
+    println!("Queued upload {} for {}{}", job.id, time(job.next_run), every);
+    println!("It runs while `dup-cli schedule run` does, results are logged to {}", spool.dir.join("schedule.log").display());
+    0
+}
+
+pub async fn command(action: ScheduleAction, shutdown: CancellationToken) -> i32 {
+    let spool = match Spool::open() {
+        Ok(spool) => spool,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return EXIT_IO;
+        }
+    };
+    match action {
+        ScheduleAction::Run { once } => run(&spool, once, shutdown).await,
+        ScheduleAction::List => {
+            let jobs = spool.jobs();
+            if jobs.is_empty() {
+                println!("No queued uploads");
+            }
+            for job in jobs {
+                let every = job.every.map(|every| format!(" every {}", interval(every))).unwrap_or_default();
+                let attempts = match job.attempts {
+                    0 => String::new(),
+                    attempts => format!(" ({} failed)", attempts),
+                };
+                println!("{:<17}  {}{}{}  {}", job.id, time(job.next_run), every, attempts, job.command_line());
+            }
+            0
+        }
+        ScheduleAction::Remove { id } if !valid_id(&id) || !spool.jobs().iter().any(|job| job.id == id) => {
+            eprintln!("Error: no queued upload {}, see `dup-cli schedule list`", id);
+            EXIT_CONFIG
+        }
+        ScheduleAction::Remove { id } => match spool.remove(&id) {
+            Ok(()) => {
+                println!("Removed upload {}", id);
+                0
+            }
+            Err(e) => {
+                eprintln!("Error: upload {}: {}", id, e);
+                EXIT_IO
+            }
+        },
+    }
+}
+
+// runs the due jobs one after another, each with its own connections and --jobs
+async fn run(spool: &Spool, once: bool, shutdown: CancellationToken) -> i32 {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return EXIT_IO;
+        }
+    };
+    if !once {
+        println!("Running queued uploads from {}, press Ctrl-C to stop", spool.dir.display());
+    }
+
+    while !shutdown.is_cancelled() {
+        let now = Local::now();
+        let mut jobs = spool.jobs().into_iter().peekable();
+        let Some(mut job) = jobs.next_if(|job| job.next_run <= now) else {
+            if once {
+                break;
+            }
+            // until the next job, or a look for jobs queued in the meantime
+            let wait = jobs
+                .peek()
+                .and_then(|job| (job.next_run - now).to_std().ok())
+                .unwrap_or(POLL)
+                .min(POLL);
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => continue,
+                _ = shutdown.cancelled() => break,
+            }
+        };
+
+        // due while nothing ran it, e.g. the machine was off
+        let late = match (now - job.next_run).to_std() {
+            Ok(late) if late > POLL * 2 => format!(" (missed at {})", time(job.next_run)),
+            _ => String::new(),
+        };
+        let started = Local::now();
+        let elapsed = Instant::now();
+        // the child gets Ctrl-C too and winds down its uploads
+        let status = tokio::process::Command::new(&exe)
+            .args(&job.args)
+            .current_dir(&job.dir)
+            .status()
+            .await;
+        let prefix = format!(
+            "{} {}{} in {}:",
+            started.to_rfc3339_opts(SecondsFormat::Secs, false),
+            job.id,
+            late,
+            crate::ui::seconds(elapsed.elapsed())
+        );
+
+        let now = Local::now();
+        let failure = match status {
+            Ok(status) if status.success() => None,
+            Ok(status) if status.code() == Some(EXIT_INTERRUPTED) || shutdown.is_cancelled() => {
+                // due again, for the next runner
+                spool.log(&format!("{} interrupted: {}", prefix, job.command_line()));
+                break;
+            }
+            Ok(status) => Some(match status.code() {
+                Some(code) => format!("failed with status {}", code),
+                None => "failed, killed by a signal".to_string(),
+            }),
+            Err(e) => Some(format!("failed to start: {}", e)),
+        };
+
+        let (result, queued) = job.reschedule(failure, now);
+        spool.log(&format!("{} {}: {}", prefix, result, job.command_line()));
+
+        let saved = match queued {
+            // removed while it ran
+            _ if !spool.path(&job.id).exists() => Ok(()),
+            true => spool.save(&job),
+            // ran once, or gave up
+            false => spool.remove(&job.id),
+        };
+        if let Err(e) = saved {
+            eprintln!("Error: failed to update upload {} in {}: {}", job.id, spool.dir.display(), e);
+            return EXIT_IO;
+        }
+    }
+
+    if shutdown.is_cancelled() {
+        EXIT_INTERRUPTED
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Cli;
+    use chrono::TimeZone;
+    use clap::Parser;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, day, hour, minute, 0).unwrap()
+    }
+
+    fn new_job(next_run: DateTime<Local>, every: Option<Duration>) -> Job {
+        Job {
+            id: "20240501-231500".to_string(),
+            args: vec!["-H".to_string(), "https://example.com/upload".to_string(), "/data".to_string()],
+            dir: PathBuf::from("/home/user"),
+            next_run,
+            due: next_run,
+            every,
+            attempts: 0,
+        }
+    }
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn jobs_round_trip_through_json() {
+        let mut job = new_job(at(1, 2, 0), Some(DAY));
+        job.next_run = at(1, 2, 10);
+        job.attempts = 2;
+        let value = job.to_json();
+        let read = Job::from_json(&value).unwrap();
+        assert_eq!(read.to_json(), value);
+        assert_eq!(read.next_run, at(1, 2, 10));
+        assert_eq!(read.due, at(1, 2, 0));
+
+        let once = new_job(at(1, 2, 0), None);
+        assert_eq!(Job::from_json(&once.to_json()).unwrap().every, None);
+    }
+
+    #[test]
+    fn jobs_without_due_run_from_next_run() {
+        let mut value = new_job(at(1, 2, 0), Some(DAY)).to_json();
+        value.as_object_mut().unwrap().remove("due");
+        value.as_object_mut().unwrap().remove("attempts");
+        let read = Job::from_json(&value).unwrap();
+        assert_eq!(read.due, at(1, 2, 0));
+        assert_eq!(read.attempts, 0);
+    }
+
+    #[test]
+    fn ids_stay_in_the_spool() {
+        for id in ["../x", "a/b", "a\\b", "..", ""] {
+            assert!(!valid_id(id), "{}", id);
+            let mut value = new_job(at(1, 2, 0), None).to_json();
+            value["id"] = Value::from(id);
+            assert!(Job::from_json(&value).is_none(), "{}", id);
+        }
+        assert!(valid_id("20240501-231500-2"));
+    }
+
+    #[test]
+    fn next_interval_skips_missed_runs() {
+        let job = new_job(at(1, 2, 0), Some(DAY));
+        assert_eq!(job.next_interval(at(1, 3, 0)), Some(at(2, 2, 0)));
+        // the machine was off for two days
+        assert_eq!(job.next_interval(at(3, 9, 0)), Some(at(4, 2, 0)));
+        // right at a run, that one is taken
+        assert_eq!(job.next_interval(at(2, 2, 0)), Some(at(3, 2, 0)));
+        assert_eq!(new_job(at(1, 2, 0), None).next_interval(at(3, 9, 0)), None);
+    }
+
+    #[test]
+    fn retries_keep_the_schedule() {
+        let mut job = new_job(at(1, 2, 0), Some(DAY));
+        let (result, queued) = job.reschedule(Some("failed with status 5".to_string()), at(1, 2, 30));
+        assert!(queued);
+        assert_eq!(result, format!("failed with status 5, trying again at {}", time(at(1, 2, 40))));
+        assert_eq!((job.next_run, job.due, job.attempts), (at(1, 2, 40), at(1, 2, 0), 1));
+
+        // the retry succeeds, the next run is at 02:00 again
+        let (result, queued) = job.reschedule(None, at(1, 2, 45));
+        assert!(queued);
+        assert_eq!(result, "succeeded");
+        assert_eq!((job.next_run, job.due, job.attempts), (at(2, 2, 0), at(2, 2, 0), 0));
+    }
+
+    #[test]
+    fn retries_give_up() {
+        let mut job = new_job(at(1, 2, 0), Some(DAY));
+        for attempt in 1..MAX_ATTEMPTS {
+            job.reschedule(Some("failed".to_string()), job.next_run);
+            assert_eq!(job.attempts, attempt);
+        }
+        let (result, queued) = job.reschedule(Some("failed".to_string()), job.next_run);
+        assert!(queued);
+        assert!(result.ends_with("giving up after 3 attempts"), "{}", result);
+        assert_eq!((job.next_run, job.due, job.attempts), (at(2, 2, 0), at(2, 2, 0), 0));
+
+        // a single upload is gone once it gave up
+        let mut job = new_job(at(1, 2, 0), None);
+        job.attempts = MAX_ATTEMPTS - 1;
+        assert!(!job.reschedule(Some("failed".to_string()), at(1, 2, 5)).1);
+        let mut job = new_job(at(1, 2, 0), None);
+        assert!(!job.reschedule(None, at(1, 2, 5)).1);
+    }
+
+    #[test]
+    fn the_next_interval_run_starts_over() {
+        // every 5 minutes, the retry would come after the next run
+        let mut job = new_job(at(1, 2, 0), Some(Duration::from_secs(5 * 60)));
+        job.attempts = 1;
+        let (result, queued) = job.reschedule(Some("failed".to_string()), at(1, 2, 1));
+        assert!(queued);
+        assert_eq!(result, format!("failed, next run at {}", time(at(1, 2, 5))));
+        assert_eq!((job.next_run, job.due, job.attempts), (at(1, 2, 5), at(1, 2, 5), 0));
+    }
+
+    #[test]
+    fn intervals_print_in_the_largest_unit() {
+        assert_eq!(interval(Duration::from_secs(45)), "45s");
+        assert_eq!(interval(Duration::from_secs(90)), "90s");
+        assert_eq!(interval(Duration::from_secs(90 * 60)), "90m");
+        assert_eq!(interval(Duration::from_secs(60 * 60)), "1h");
+        assert_eq!(interval(Duration::from_secs(25 * 60 * 60)), "25h");
+        assert_eq!(interval(DAY), "1d");
+        assert_eq!(interval(DAY * 14), "2w");
+    }
+
+    #[test]
+    fn schedule_options_are_not_queued() {
+        let args = ["--at", "02:00", "-H", "https://example.com", "--every=1d", "--at=2h", "--every", "1h", "a.txt"];
+        assert_eq!(
+            without_schedule(args.iter().map(|arg| arg.to_string())),
+            ["-H", "https://example.com", "a.txt"]
+        );
+    }
+
+    #[test]
+    fn secrets_are_refused() {
+        for secret in [&["--password", "hunter2"][..], &["--passphrase", "hunter2"], &["--user", "user:hunter2"]] {
+            let cli = Cli::try_parse_from(
+                ["dup-cli", "-H", "https://example.com", "--at", "02:00"].iter().chain(secret).chain(&["a.txt"]),
+            )
+            .unwrap();
+            let error = job_args(&cli.upload, true, true).unwrap_err();
+            assert!(error.contains("would be stored"), "{}", error);
+        }
+    }
+}
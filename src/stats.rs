@@ -0,0 +1,114 @@
+use crate::uploader::{ChunkCounts, FileResult, Outcome};
+use std::time::Duration;
+
+// the spread of a duration over the uploaded files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl Percentiles {
+    // nearest rank, None without samples
+    pub fn new(mut samples: Vec<Duration>) -> Option<Self> {
+        samples.sort_unstable();
+        let max = *samples.last()?;
+        let rank = |p: usize| samples[(samples.len() * p).div_ceil(100) - 1];
+        Some(Self {
+            p50: rank(50),
+            p95: rank(95),
+            max,
+        })
+    }
+}
+
+// files that ended the same way
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Totals {
+    pub files: usize,
+    // bytes on disk
+    pub size: u64,
+    // of those, confirmed by the target in this run
+    pub acknowledged: u64,
+}
+
+impl Totals {
+    fn add(&mut self, result: &FileResult) {
+        self.files += 1;
+        self.size += result.size;
+        self.acknowledged += result.acknowledged;
+    }
+}
+
+// numbers of a whole run, from the results of `Uploader::upload_all`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub succeeded: Totals,
+    // including files cancelled while they were uploaded
+    pub failed: Totals,
+    // not started after a shutdown
+    pub skipped: Totals,
+    // bytes the target kept from earlier runs, not sent again
+    pub resumed: u64,
+    // of the chunked files, None when there were none
+    pub chunks: Option<ChunkCounts>,
+    // wall clock time of the run
+    pub elapsed: Duration,
+    // uploaded files per file, from start to result
+    pub latency: Option<Percentiles>,
+    pub first_byte: Option<Percentiles>,
+}
+
+impl Stats {
+    pub fn new(results: &[FileResult], elapsed: Duration) -> Self {
+        let mut succeeded = Totals::default();
+        let mut failed = Totals::default();
+        let mut skipped = Totals::default();
+        let mut chunks: Option<ChunkCounts> = None;
+        for result in results {
+            match result.outcome {
+                Outcome::Uploaded(_) => succeeded.add(result),
+                Outcome::Failed(_) => failed.add(result),
+                Outcome::NotStarted => skipped.add(result),
+            }
+            if let Some(counts) = result.chunks {
+                let sum = chunks.get_or_insert_with(ChunkCounts::default);
+                sum.total += counts.total;
+                sum.acknowledged += counts.acknowledged;
+                sum.resumed += counts.resumed;
+                sum.retried += counts.retried;
+            }
+        }
+
+        let uploaded: Vec<&FileResult> = results
+            .iter()
+            .filter(|result| matches!(result.outcome, Outcome::Uploaded(_)))
+            .collect();
+        Self {
+            succeeded,
+            failed,
+            skipped,
+            resumed: results.iter().map(|result| result.resumed).sum(),
+            chunks,
+            elapsed,
+            latency: Percentiles::new(uploaded.iter().map(|result| result.elapsed).collect()),
+            first_byte: Percentiles::new(uploaded.iter().filter_map(|result| result.first_byte).collect()),
+        }
+    }
+
+    // bytes the target confirmed in this run, of finished and failed files
+    pub fn acknowledged(&self) -> u64 {
+        self.succeeded.acknowledged + self.failed.acknowledged
+    }
+
+    // acknowledged bytes per second over the whole run
+    pub fn throughput(&self) -> Option<f64> {
+        throughput(self.acknowledged(), self.elapsed)
+    }
+}
+
+// bytes per second, None when no time passed
+pub fn throughput(bytes: u64, elapsed: Duration) -> Option<f64> {
+    (!elapsed.is_zero()).then(|| bytes as f64 / elapsed.as_secs_f64())
+}
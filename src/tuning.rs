@@ -0,0 +1,433 @@
+use crate::units::parse_size;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+
+// --chunk-size auto: chunks take about this long to send, or longer on slow
+// round trips so the request overhead stays small
+const TARGET_CHUNK_TIME: Duration = Duration::from_secs(2);
+const CHUNK_TIME_PER_RTT: u32 = 8;
+// per connection, until the first chunks were measured
+const ASSUMED_RATE: f64 = (4 * MIB) as f64;
+const MIN_AUTO_CHUNK: u64 = MIB;
+const MAX_AUTO_CHUNK: u64 = 64 * MIB;
+const AUTO_CHUNK_ALIGN: u64 = 64 * KIB;
+// servers like S3 refuse more parts than this
+const MAX_AUTO_CHUNKS: u64 = 10_000;
+
+// --chunk-concurrency auto starts here and grows while it pays off
+const INITIAL_WINDOW: usize = 2;
+// a larger window has to be this much faster to be kept
+const MIN_GAIN: f64 = 1.1;
+// rounds at a steady window before trying a larger one again
+const PROBE_ROUNDS: u32 = 8;
+// attempts per chunk, only with auto concurrency
+pub const MAX_CHUNK_ATTEMPTS: u32 = 4;
+
+// --chunk-size
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ChunkSize {
+    Auto,
+    Fixed(usize),
+}
+
+impl std::str::FromStr for ChunkSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ChunkSize::Auto),
+            _ => Ok(ChunkSize::Fixed(parse_size(s)?.try_into().map_err(|_| "Chunk size too large")?)),
+        }
+    }
+}
+
+// --chunk-concurrency, the backend's limit when not given
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Concurrency {
+    Auto,
+    Fixed(usize),
+}
+
+impl std::str::FromStr for Concurrency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Concurrency::Auto),
+            _ => match s.parse() {
+                Ok(0) | Err(_) => Err("Invalid concurrency, expected auto or a number of at least 1".into()),
+                Ok(n) => Ok(Concurrency::Fixed(n)),
+            },
+        }
+    }
+}
+
+// what the uploads so far tell about the link, shared by all files
+#[derive(Debug, Default)]
+pub struct LinkEstimate {
+    rtt: Mutex<Option<Duration>>,
+    // bytes per second of a single chunk request, smoothed
+    rate: Mutex<Option<f64>>,
+}
+
+impl LinkEstimate {
+    // the best of a few TCP handshakes with the upload host
+    pub async fn probe_rtt(&self, url: &str) {
+        let Ok(url) = reqwest::Url::parse(url) else {
+            return;
+        };
+        let port = url.port_or_known_default().or((url.scheme() == "sftp").then_some(22));
+        let (Some(host), Some(port)) = (url.host_str(), port) else {
+            return;
+        };
+
+        let mut best: Option<Duration> = None;
+        for _ in 0..3 {
+            let start = Instant::now();
+            match tokio::time::timeout(Duration::from_secs(5), TcpStream::connect((host, port))).await {
+                Ok(Ok(_)) => best = Some(best.map_or(start.elapsed(), |best| best.min(start.elapsed()))),
+                _ => break,
+            }
+        }
+        *self.rtt.lock().unwrap() = best;
+    }
+
+    pub fn record(&self, bytes: u64, elapsed: Duration) {
+        if bytes == 0 || elapsed.is_zero() {
+            return;
+        }
+        let sample = bytes as f64 / elapsed.as_secs_f64();
+        let mut rate = self.rate.lock().unwrap();
+        *rate = Some(match *rate {
+            Some(rate) => rate * 0.8 + sample * 0.2,
+            None => sample,
+        });
+    }
+
+    // a chunk size for --chunk-size auto; backends still apply their own limits
+    pub fn chunk_size(&self, file_size: u64) -> usize {
+        let rate = self.rate.lock().unwrap().unwrap_or(ASSUMED_RATE);
+        let rtt = self.rtt.lock().unwrap().unwrap_or_default();
+        let chunk_time = TARGET_CHUNK_TIME.max(rtt * CHUNK_TIME_PER_RTT);
+
+        let size = (rate * chunk_time.as_secs_f64()) as u64;
+        // no bigger than the file needs, no more chunks than servers accept
+        let size = size
+            .min(file_size)
+            .clamp(MIN_AUTO_CHUNK, MAX_AUTO_CHUNK)
+            .max(file_size.div_ceil(MAX_AUTO_CHUNKS));
+        size.div_ceil(AUTO_CHUNK_ALIGN).saturating_mul(AUTO_CHUNK_ALIGN) as usize
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Phase {
+    // doubling every round
+    SlowStart,
+    // one more every round
+    Additive,
+    // holding, until the next probe
+    Steady,
+}
+
+// chunks of one file in flight; with auto concurrency it grows like a TCP
+// congestion window while throughput improves, and halves when chunks fail
+#[derive(Debug)]
+pub struct Window {
+    limit: usize,
+    max: usize,
+    adaptive: bool,
+    phase: Phase,
+    // completions still owed to the old window after a change, not measured
+    warm_up: usize,
+    // the current round: chunks and bytes finished since it started
+    round_start: Instant,
+    round_bytes: u64,
+    round_chunks: usize,
+    // throughput of the window before the last increase
+    previous_rate: f64,
+    steady_rounds: u32,
+    // failures of chunks that were in flight together count once
+    backed_off: bool,
+}
+
+impl Window {
+    pub fn new(concurrency: Option<Concurrency>, max: usize) -> Self {
+        let (limit, adaptive) = match concurrency {
+            None => (max, false),
+            Some(Concurrency::Fixed(n)) => (n.min(max), false),
+            Some(Concurrency::Auto) => (INITIAL_WINDOW.min(max), true),
+        };
+        Self {
+            limit,
+            max,
+            adaptive,
+            phase: Phase::SlowStart,
+            warm_up: 0,
+            round_start: Instant::now(),
+            round_bytes: 0,
+            round_chunks: 0,
+            previous_rate: 0.0,
+            steady_rounds: 0,
+            backed_off: false,
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    // failed chunks are only retried when the window can back off
+    pub fn adaptive(&self) -> bool {
+        self.adaptive
+    }
+
+    pub fn on_success(&mut self, bytes: u64) {
+        if !self.adaptive {
+            return;
+        }
+        self.backed_off = false;
+        if self.warm_up > 0 {
+            self.warm_up -= 1;
+            if self.warm_up == 0 {
+                self.next_round(0);
+            }
+            return;
+        }
+
+        self.round_bytes += bytes;
+        self.round_chunks += 1;
+        // a round is one window of chunks, like a round trip for TCP
+        if self.round_chunks < self.limit {
+            return;
+        }
+
+        let rate = self.round_bytes as f64 / self.round_start.elapsed().as_secs_f64().max(f64::EPSILON);
+        let improved = rate > self.previous_rate * MIN_GAIN;
+        let limit = self.limit;
+
+        match (self.phase, improved) {
+            (Phase::SlowStart, true) => self.limit = (self.limit * 2).min(self.max),
+            (Phase::Additive, true) => self.limit = (self.limit + 1).min(self.max),
+            // more parallel chunks stopped helping, go back to the last window
+            (Phase::SlowStart, false) => {
+                self.limit = (self.limit / 2).max(1);
+                self.phase = Phase::Steady;
+            }
+            (Phase::Additive, false) => {
+                self.limit = (self.limit - 1).max(1);
+                self.phase = Phase::Steady;
+            }
+            // conditions change, try one more now and then
+            (Phase::Steady, _) => {
+                self.steady_rounds += 1;
+                if self.steady_rounds >= PROBE_ROUNDS && self.limit < self.max {
+                    self.steady_rounds = 0;
+                    self.limit += 1;
+                    self.phase = Phase::Additive;
+                }
+            }
+        }
+        // the rate to beat is the one of the smaller window
+        if self.limit > limit || self.phase == Phase::Steady {
+            self.previous_rate = rate;
+        }
+        self.next_round(if self.limit != limit { limit } else { 0 });
+    }
+
+    pub fn on_error(&mut self) {
+        if !self.adaptive || self.backed_off {
+            return;
+        }
+        self.backed_off = true;
+        let limit = self.limit;
+        self.limit = (self.limit / 2).max(1);
+        self.previous_rate = 0.0;
+        self.phase = Phase::Additive;
+        self.next_round(limit);
+    }
+
+    // `warm_up`: chunks of the old window that may still be in flight
+    fn next_round(&mut self, warm_up: usize) {
+        self.warm_up = warm_up;
+        self.round_start = Instant::now();
+        self.round_bytes = 0;
+        self.round_chunks = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * MIB;
+
+    // one round of the window at `rate` bytes per second, after the chunks of
+    // the previous window came back
+    fn round(window: &mut Window, rate: u64) {
+        while window.warm_up > 0 {
+            window.on_success(0);
+        }
+        window.round_start = Instant::now() - Duration::from_secs(1);
+        let chunks = window.limit;
+        for _ in 0..chunks {
+            window.on_success(rate / chunks as u64);
+        }
+    }
+
+    #[test]
+    fn fixed_windows_stay() {
+        let mut window = Window::new(Some(Concurrency::Fixed(4)), 8);
+        assert_eq!(window.limit(), 4);
+        assert!(!window.adaptive());
+        round(&mut window, MIB);
+        window.on_error();
+        assert_eq!(window.limit(), 4);
+        assert_eq!(Window::new(Some(Concurrency::Fixed(16)), 8).limit(), 8);
+        assert_eq!(Window::new(None, 8).limit(), 8);
+    }
+
+    #[test]
+    fn slow_start_doubles_up_to_the_max() {
+        let mut window = Window::new(Some(Concurrency::Auto), 12);
+        assert_eq!(window.limit(), INITIAL_WINDOW);
+        for (rate, limit) in [(10, 4), (20, 8), (40, 12), (80, 12)] {
+            round(&mut window, rate * MIB);
+            assert_eq!(window.limit(), limit);
+        }
+        assert_eq!(window.phase, Phase::SlowStart);
+    }
+
+    #[test]
+    fn slow_start_goes_back_without_gain() {
+        let mut window = Window::new(Some(Concurrency::Auto), 16);
+        round(&mut window, 10 * MIB);
+        assert_eq!(window.limit(), 4);
+        // less than MIN_GAIN faster
+        round(&mut window, 10 * MIB + MIB / 2);
+        assert_eq!(window.limit(), 2);
+        assert_eq!(window.phase, Phase::Steady);
+    }
+
+    #[test]
+    fn steady_windows_probe_for_more() {
+        let mut window = Window::new(Some(Concurrency::Auto), 16);
+        round(&mut window, 10 * MIB);
+        round(&mut window, 10 * MIB);
+        assert_eq!((window.limit(), window.phase), (2, Phase::Steady));
+        for _ in 1..PROBE_ROUNDS {
+            round(&mut window, 10 * MIB);
+            assert_eq!(window.limit(), 2);
+        }
+        round(&mut window, 10 * MIB);
+        assert_eq!((window.limit(), window.phase), (3, Phase::Additive));
+        // one more pays off, then one more does not
+        round(&mut window, 20 * MIB);
+        assert_eq!(window.limit(), 4);
+        round(&mut window, 20 * MIB);
+        assert_eq!((window.limit(), window.phase), (3, Phase::Steady));
+    }
+
+    #[test]
+    fn windows_stay_below_the_max() {
+        let mut window = Window::new(Some(Concurrency::Auto), 3);
+        for rate in [10, 20, 40, 80] {
+            round(&mut window, rate * MIB);
+            assert!(window.limit() <= 3);
+        }
+        assert_eq!(window.limit(), 3);
+        // no faster than the window below it, then probing stops at the max too
+        round(&mut window, 10 * MIB);
+        assert_eq!(window.phase, Phase::Steady);
+        let mut rate = 10;
+        for _ in 0..PROBE_ROUNDS * 4 {
+            rate *= 2;
+            round(&mut window, rate * MIB);
+            assert!(window.limit() <= 3);
+        }
+        assert_eq!(window.limit(), 3);
+    }
+
+    #[test]
+    fn errors_halve_the_window() {
+        let mut window = Window::new(Some(Concurrency::Auto), 16);
+        round(&mut window, 10 * MIB);
+        round(&mut window, 20 * MIB);
+        assert_eq!(window.limit(), 8);
+        window.on_error();
+        assert_eq!((window.limit(), window.phase), (4, Phase::Additive));
+        // the other chunks that were in flight with it
+        window.on_error();
+        assert_eq!(window.limit(), 4);
+        window.on_success(MIB);
+        window.on_error();
+        assert_eq!(window.limit(), 2);
+        for _ in 0..3 {
+            window.on_success(MIB);
+            window.on_error();
+        }
+        assert_eq!(window.limit(), 1);
+        // and grow one at a time again
+        round(&mut window, 10 * MIB);
+        assert_eq!(window.limit(), 2);
+    }
+
+    #[test]
+    fn single_windows_stay_single() {
+        let mut window = Window::new(Some(Concurrency::Auto), 1);
+        assert_eq!(window.limit(), 1);
+        round(&mut window, 10 * MIB);
+        round(&mut window, 20 * MIB);
+        window.on_error();
+        assert_eq!(window.limit(), 1);
+    }
+
+    #[test]
+    fn chunk_sizes_follow_the_link() {
+        let link = LinkEstimate::default();
+        // 4MiB/s assumed for two seconds
+        assert_eq!(link.chunk_size(GIB), 8 * MIB as usize);
+        link.record(100 * MIB, Duration::from_secs(1));
+        assert_eq!(link.chunk_size(GIB), MAX_AUTO_CHUNK as usize);
+
+        // slow round trips take longer chunks
+        let link = LinkEstimate::default();
+        *link.rtt.lock().unwrap() = Some(Duration::from_millis(500));
+        assert_eq!(link.chunk_size(GIB), 16 * MIB as usize);
+    }
+
+    #[test]
+    fn chunk_sizes_are_bounded_and_aligned() {
+        let link = LinkEstimate::default();
+        // small files still get the smallest chunk
+        assert_eq!(link.chunk_size(100 * KIB), MIN_AUTO_CHUNK as usize);
+        assert_eq!(link.chunk_size(0), MIN_AUTO_CHUNK as usize);
+
+        link.record(3_000_000, Duration::from_secs(1));
+        let size = link.chunk_size(GIB) as u64;
+        assert_eq!(size, 6_000_000u64.div_ceil(AUTO_CHUNK_ALIGN) * AUTO_CHUNK_ALIGN);
+
+        // no more chunks than servers accept, even above the largest chunk
+        let file_size = 1024 * GIB;
+        let size = link.chunk_size(file_size) as u64;
+        assert!(size > MAX_AUTO_CHUNK);
+        assert_eq!(size % AUTO_CHUNK_ALIGN, 0);
+        assert!(file_size.div_ceil(size) <= MAX_AUTO_CHUNKS);
+    }
+
+    #[test]
+    fn rates_are_smoothed() {
+        let link = LinkEstimate::default();
+        link.record(0, Duration::from_secs(1));
+        link.record(MIB, Duration::ZERO);
+        assert_eq!(*link.rate.lock().unwrap(), None);
+        link.record(1_000_000, Duration::from_secs(1));
+        link.record(2_000_000, Duration::from_secs(1));
+        let rate = link.rate.lock().unwrap().unwrap();
+        assert!((rate - 1_200_000.0).abs() < 1.0, "{}", rate);
+    }
+}
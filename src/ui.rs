@@ -0,0 +1,424 @@
+use dup_cli::stats::{throughput, Percentiles, Totals};
+use dup_cli::files::Filtered;
+use dup_cli::units::Units;
+use dup_cli::{ChunkCounts, Error, Event, Observer, Outcome, Stats, UploadFile, UploadReport};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// the text output: a line when a file starts and when it is done, and a line
+// per chunk or, with --progress, a bar per file
+#[derive(Debug)]
+pub struct Console {
+    bars: Option<Mutex<HashMap<PathBuf, ProgressBar>>>,
+    units: Units,
+}
+
+impl Console {
+    pub fn new(progress: bool, units: Units) -> Self {
+        Self {
+            bars: progress.then(Mutex::default),
+            units,
+        }
+    }
+}
+
+impl Observer for Console {
+    fn on_event(&self, event: &Event) {
+        match event {
+            Event::Started {
+                file,
+                name,
+                size,
+                chunk_size,
+                ..
+            } => {
+                println!("Starting upload of {} [{}]", file.path.display(), self.units.size(*size));
+                if let Some(chunk_size) = chunk_size {
+                    println!("Uploading file {} with chunk size {}", name, self.units.size(*chunk_size as u64));
+                }
+                if let Some(bars) = &self.bars {
+                    bars.lock().unwrap().insert(file.path.clone(), new_progress_bar(*size, self.units));
+                }
+            }
+            Event::Resumed { file, offset } => {
+                println!("Resuming upload of {} at byte {}", file.path.display(), offset);
+            }
+            Event::BytesSent { file, sent, .. } => {
+                if let Some(bars) = &self.bars {
+                    if let Some(pb) = bars.lock().unwrap().get(&file.path) {
+                        pb.set_position(*sent);
+                    }
+                }
+            }
+            Event::ChunkDone {
+                index,
+                total,
+                response,
+                resumed,
+                ..
+            } if self.bars.is_none() => match resumed {
+                true => println!("Chunk {} of {} already uploaded", index + 1, total),
+                false => println!("Chunk {} of {} uploaded: {:?}", index + 1, total, response.unwrap_or_default()),
+            },
+            Event::Retried {
+                file,
+                index,
+                error,
+                in_flight,
+                ..
+            } => {
+                eprintln!(
+                    "Warning: chunk {} of {} failed ({}), retrying with {} in flight",
+                    index + 1,
+                    file.path.display(),
+                    error,
+                    in_flight
+                );
+            }
+            Event::Warning { message, .. } => eprintln!("Warning: {}", message),
+            Event::Resumable {
+                name,
+                location: Some(location),
+                ..
+            } => eprintln!("Upload of {} can be resumed from {}", name, location),
+            Event::Resumable { name, location: None, .. } => {
+                eprintln!("Upload of {} can be resumed by running the same command again", name)
+            }
+            Event::Finished { result } | Event::Failed { result, .. } => {
+                if let Some(bars) = &self.bars {
+                    if let Some(pb) = bars.lock().unwrap().remove(&result.file.path) {
+                        let msg = format!("Uploaded {} bytes", pb.position());
+                        // finishing would fill the bar of a failed upload
+                        match result.outcome {
+                            Outcome::Uploaded(_) => pb.finish_with_message(msg),
+                            _ => pb.abandon_with_message(msg),
+                        }
+                    }
+                }
+
+                let elapsed = seconds(result.elapsed);
+                let path = result.file.path.display();
+                let size = self.units.size(result.size);
+                let speed = speed(throughput(result.acknowledged, result.elapsed), self.units);
+                match &result.outcome {
+                    Outcome::Uploaded(UploadReport {
+                        response: Some(download_link),
+                        ..
+                    }) => println!("[{}][{}][{}][{}] - Download: {}", elapsed, path, size, speed, download_link),
+                    Outcome::Uploaded(_) => println!("[{}][{}][{}][{}]", elapsed, path, size, speed),
+                    Outcome::Failed(Error::Cancelled) => println!("[{}][{}][{}] - Cancelled", elapsed, path, size),
+                    Outcome::Failed(e) => eprintln!("[{}] Error: {}", elapsed, e),
+                    Outcome::NotStarted => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn new_progress_bar(file_size: u64, units: Units) -> ProgressBar {
+    let template = match units {
+        Units::Iec => "[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} ({eta})",
+        Units::Si => "[{elapsed_precise}] {bar:40.cyan/blue} {decimal_bytes}/{decimal_total_bytes} ({eta})",
+        Units::Bytes => "[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len}B ({eta})",
+    };
+    let pb = ProgressBar::new(file_size);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template(template)
+            .unwrap()
+            .progress_chars("##-"),
+    );
+    pb
+}
+
+// --json: one object per line on stdout for every event, progress in steps
+// of 1% per file
+#[derive(Debug, Default)]
+pub struct JsonLines {
+    // the last reported percentage of each file
+    percent: Mutex<HashMap<PathBuf, u64>>,
+}
+
+impl JsonLines {
+    // also prints the summary after the last event
+    pub fn print(line: Value) {
+        // a whole line at a time, files report from several tasks
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", line);
+    }
+}
+
+impl Observer for JsonLines {
+    fn on_event(&self, event: &Event) {
+        let line = match event {
+            Event::Queued { file } => json!({ "event": "queued", "path": file.path, "relative_path": file.relative_path }),
+            Event::Started {
+                file,
+                name,
+                size,
+                chunk_size,
+                total_chunks,
+            } => json!({
+                "event": "started",
+                "path": file.path,
+                "name": name,
+                "size": size,
+                "chunk_size": chunk_size,
+                "total_chunks": total_chunks,
+            }),
+            Event::Resumed { file, offset } => json!({ "event": "resumed", "path": file.path, "offset": offset }),
+            Event::BytesSent { file, sent, size } => {
+                let percent = (sent * 100).checked_div(*size).unwrap_or(100);
+                let mut reported = self.percent.lock().unwrap();
+                let last = reported.entry(file.path.clone()).or_insert(u64::MAX);
+                if *last == percent {
+                    return;
+                }
+                *last = percent;
+                json!({ "event": "bytes_sent", "path": file.path, "sent": sent, "size": size })
+            }
+            Event::ChunkDone {
+                file,
+                index,
+                total,
+                response,
+                resumed,
+            } => json!({
+                "event": "chunk_done",
+                "path": file.path,
+                "index": index,
+                "total": total,
+                "response": response,
+                "resumed": resumed,
+            }),
+            Event::Retried {
+                file,
+                index,
+                attempt,
+                error,
+                in_flight,
+            } => json!({
+                "event": "retried",
+                "path": file.path,
+                "index": index,
+                "attempt": attempt,
+                "error": error_json(error),
+                "in_flight": in_flight,
+            }),
+            Event::Warning { file, message } => json!({ "event": "warning", "path": file.path, "message": message }),
+            Event::Resumable { file, name, location } => {
+                json!({ "event": "resumable", "path": file.path, "name": name, "location": location })
+            }
+            Event::Finished { result } => {
+                let response = match &result.outcome {
+                    Outcome::Uploaded(report) => report.response.as_deref(),
+                    _ => None,
+                };
+                self.percent.lock().unwrap().remove(&result.file.path);
+                json!({
+                    "event": "finished",
+                    "path": result.file.path,
+                    "size": result.size,
+                    "elapsed": result.elapsed.as_secs_f64(),
+                    "acknowledged": result.acknowledged,
+                    "resumed": result.resumed,
+                    "chunks": result.chunks.map(chunks_json),
+                    "first_byte": result.first_byte.map(|first_byte| first_byte.as_secs_f64()),
+                    "throughput": throughput(result.acknowledged, result.elapsed),
+                    "response": response,
+                })
+            }
+            Event::Failed { result, error } => {
+                self.percent.lock().unwrap().remove(&result.file.path);
+                json!({
+                    "event": "failed",
+                    "path": result.file.path,
+                    "size": result.size,
+                    "elapsed": result.elapsed.as_secs_f64(),
+                    "acknowledged": result.acknowledged,
+                    "resumed": result.resumed,
+                    "chunks": result.chunks.map(chunks_json),
+                    "error": error_json(error),
+                })
+            }
+            Event::Skipped { file } => json!({ "event": "skipped", "path": file.path }),
+        };
+        JsonLines::print(line);
+    }
+}
+
+// the message, the kind as in the summary, and the status of HTTP errors
+fn error_json(error: &Error) -> Value {
+    let status = match error {
+        Error::Http { status, .. } => Some(status.as_u16()),
+        _ => None,
+    };
+    json!({ "message": error.to_string(), "kind": error.kind(), "status": status })
+}
+
+// the table after the last file
+pub fn print_stats(stats: &Stats, units: Units) {
+    println!("{:<20}{}", "Total time:", seconds(stats.elapsed));
+    // what the target confirmed, failed files can have sent some of their chunks
+    println!("{:<20}{}", "Total size:", units.size(stats.acknowledged()));
+    println!("{:<20}{}", "Succeeded:", totals(&stats.succeeded, units));
+    if stats.failed.files > 0 {
+        println!("{:<20}{}", "Failed:", totals(&stats.failed, units));
+    }
+    if stats.skipped.files > 0 {
+        println!("{:<20}{}", "Skipped:", totals(&stats.skipped, units));
+    }
+    if stats.resumed > 0 {
+        println!("{:<20}{} already on the target", "Resumed:", units.size(stats.resumed));
+    }
+    if let Some(chunks) = stats.chunks {
+        println!(
+            "{:<20}{} of {} acknowledged, {} resumed, {} retried",
+            "Chunks:", chunks.acknowledged, chunks.total, chunks.resumed, chunks.retried
+        );
+    }
+    println!("{:<20}{}", "Average speed:", speed(stats.throughput(), units));
+    if let Some(latency) = stats.latency {
+        println!("{:<20}{}", "File time:", percentiles(latency));
+    }
+    if let Some(first_byte) = stats.first_byte {
+        println!("{:<20}{}", "Time to first byte:", percentiles(first_byte));
+    }
+}
+
+// durations in seconds, throughput in bytes per second
+pub fn stats_json(stats: &Stats) -> Value {
+    let percentiles = |p: Option<Percentiles>| {
+        p.map(|p| json!({ "p50": p.p50.as_secs_f64(), "p95": p.p95.as_secs_f64(), "max": p.max.as_secs_f64() }))
+    };
+    let totals = |totals: &Totals| {
+        json!({ "files": totals.files, "size": totals.size, "acknowledged": totals.acknowledged })
+    };
+    json!({
+        "acknowledged": stats.acknowledged(),
+        "succeeded": totals(&stats.succeeded),
+        "failed": totals(&stats.failed),
+        "skipped": totals(&stats.skipped),
+        "resumed": stats.resumed,
+        "chunks": stats.chunks.map(chunks_json),
+        "elapsed": stats.elapsed.as_secs_f64(),
+        "throughput": stats.throughput(),
+        "latency": percentiles(stats.latency),
+        "first_byte": percentiles(stats.first_byte),
+    })
+}
+
+fn chunks_json(chunks: ChunkCounts) -> Value {
+    json!({
+        "total": chunks.total,
+        "acknowledged": chunks.acknowledged,
+        "resumed": chunks.resumed,
+        "retried": chunks.retried,
+    })
+}
+
+// e.g. `2 files, 4.00MB of 57.22MB`
+fn totals(totals: &Totals, units: Units) -> String {
+    let files = match totals.files {
+        1 => "1 file".to_string(),
+        n => format!("{} files", n),
+    };
+    match totals.acknowledged == totals.size {
+        true => format!("{}, {}", files, units.size(totals.size)),
+        false => format!("{}, {} of {}", files, units.size(totals.acknowledged), units.size(totals.size)),
+    }
+}
+
+fn percentiles(p: Percentiles) -> String {
+    format!("p50 {}  p95 {}  max {}", seconds(p.p50), seconds(p.p95), seconds(p.max))
+}
+
+pub fn seconds(duration: Duration) -> String {
+    format!("{:.2}s", duration.as_secs_f64())
+}
+
+fn speed(throughput: Option<f64>, units: Units) -> String {
+    match throughput {
+        Some(throughput) => units.rate(throughput),
+        None => "-".to_string(),
+    }
+}
+
+// e.g. `[("http", 2), ("timeout", 1)]`, in order of appearance
+pub fn tally<'a>(kinds: impl Iterator<Item = &'a str>) -> Vec<(&'a str, usize)> {
+    let mut tally: Vec<(&str, usize)> = vec![];
+    for kind in kinds {
+        match tally.iter_mut().find(|(known, _)| *known == kind) {
+            Some((_, count)) => *count += 1,
+            None => tally.push((kind, 1)),
+        }
+    }
+    tally
+}
+
+// `http: 2, timeout: 1`
+pub fn tally_text(tally: &[(&str, usize)]) -> String {
+    let kinds: Vec<String> = tally.iter().map(|(kind, count)| format!("{}: {}", kind, count)).collect();
+    kinds.join(", ")
+}
+
+pub fn tally_json(tally: &[(&str, usize)]) -> Value {
+    Value::Object(tally.iter().map(|(kind, count)| (kind.to_string(), json!(count))).collect())
+}
+
+// --dry-run, what would be uploaded and what the filters left out
+pub fn print_dry_run(selected: &[UploadFile], filtered: &[Filtered], json: bool, units: Units) {
+    let mut total_size = 0;
+    for file in selected {
+        let size = std::fs::metadata(&file.path).map_or(0, |metadata| metadata.len());
+        total_size += size;
+        match json {
+            true => JsonLines::print(json!({ "event": "selected", "path": file.path, "size": size })),
+            false => println!("Would upload {} [{}]", file.path.display(), units.size(size)),
+        }
+    }
+    for filtered in filtered {
+        match json {
+            true => JsonLines::print(json!({
+                "event": "filtered",
+                "path": filtered.file.path,
+                "size": filtered.size,
+                "reason": filtered.reason.to_string(),
+                "kind": filtered.reason.kind(),
+            })),
+            false => println!(
+                "Filtered {} [{}]: {}",
+                filtered.file.path.display(),
+                units.size(filtered.size),
+                filtered.reason
+            ),
+        }
+    }
+
+    let reasons = tally(filtered.iter().map(|filtered| filtered.reason.kind()));
+    match json {
+        true => JsonLines::print(json!({
+            "event": "summary",
+            "dry_run": true,
+            "selected": selected.len(),
+            "size": total_size,
+            "filtered": tally_json(&reasons),
+        })),
+        false if reasons.is_empty() => {
+            println!("Dry run: {} files to upload ({})", selected.len(), units.size(total_size));
+        }
+        false => println!(
+            "Dry run: {} files to upload ({}), {} filtered ({})",
+            selected.len(),
+            units.size(total_size),
+            filtered.len(),
+            tally_text(&reasons)
+        ),
+    }
+}
@@ -0,0 +1,186 @@
+const KIB: u64 = 1024;
+const KB: u64 = 1000;
+
+const IEC: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+const SI: [&str; 6] = ["kB", "MB", "GB", "TB", "PB", "EB"];
+
+// --units, how sizes and speeds are printed
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Units {
+    // powers of 1024: KiB, MiB, GiB, TiB
+    #[default]
+    Iec,
+    // powers of 1000: kB, MB, GB, TB
+    Si,
+    // exact byte counts
+    Bytes,
+}
+
+impl std::str::FromStr for Units {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "iec" => Ok(Units::Iec),
+            "si" => Ok(Units::Si),
+            "bytes" => Ok(Units::Bytes),
+            _ => Err(format!("Invalid units {}, expected iec, si or bytes", s)),
+        }
+    }
+}
+
+impl Units {
+    // `28.61MiB`, `30.00MB` or `30000000B`
+    pub fn size(self, bytes: u64) -> String {
+        let (base, labels) = match self {
+            Units::Iec => (KIB, IEC),
+            Units::Si => (KB, SI),
+            Units::Bytes => return format!("{}B", bytes),
+        };
+        if bytes < base {
+            return format!("{}B", bytes);
+        }
+
+        let mut value = bytes as f64 / base as f64;
+        let mut label = 0;
+        // compared as printed, so 1023.999KiB becomes 1.00MiB
+        while (value * 100.0).round() >= (base * 100) as f64 && label + 1 < labels.len() {
+            value /= base as f64;
+            label += 1;
+        }
+        format!("{:.2}{}", value, labels[label])
+    }
+
+    // bytes per second, e.g. `34.06MiB/s`
+    pub fn rate(self, bytes_per_second: f64) -> String {
+        format!("{}/s", self.size(bytes_per_second as u64))
+    }
+}
+
+// `8388608`, `8MiB`, `8M`, `1.5GB`, `2TiB`; K, M, G, T and P alone are binary
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let invalid = || format!("Invalid size {}", s);
+
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => KIB,
+        "m" | "mib" => KIB.pow(2),
+        "g" | "gib" => KIB.pow(3),
+        "t" | "tib" => KIB.pow(4),
+        "p" | "pib" => KIB.pow(5),
+        "kb" => KB,
+        "mb" => KB.pow(2),
+        "gb" => KB.pow(3),
+        "tb" => KB.pow(4),
+        "pb" => KB.pow(5),
+        _ => return Err(format!("Invalid size {}, expected e.g. 8MiB, 500KB or 1048576", s)),
+    };
+
+    // decimal digits, not f64: 4.1GB is 4099999999.9999995 bytes as a float
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if whole.is_empty() && fraction.is_empty() || fraction.contains('.') {
+        return Err(invalid());
+    }
+    let digits = |digits: &str| -> Result<u128, String> {
+        match digits {
+            "" => Ok(0),
+            _ => digits.parse().map_err(|_| format!("Invalid size {}, too large", s)),
+        }
+    };
+    let scale = 10u128
+        .checked_pow(fraction.len() as u32)
+        .ok_or_else(|| format!("Invalid size {}, too many decimals", s))?;
+    let fraction_bytes = digits(fraction)?
+        .checked_mul(multiplier as u128)
+        .ok_or_else(|| format!("Invalid size {}, too many decimals", s))?;
+    // rounded to the nearest byte, only a fraction of a single byte is refused
+    if multiplier == 1 && fraction_bytes % scale != 0 {
+        return Err(format!("Invalid size {}, not a whole number of bytes", s));
+    }
+    let bytes = digits(whole)?
+        .checked_mul(multiplier as u128)
+        .and_then(|bytes| bytes.checked_add((fraction_bytes + scale / 2) / scale))
+        .filter(|&bytes| bytes <= u64::MAX as u128)
+        .ok_or_else(|| format!("Invalid size {}, too large", s))?;
+    Ok(bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_switch_units_at_the_base() {
+        assert_eq!(Units::Iec.size(0), "0B");
+        assert_eq!(Units::Iec.size(1023), "1023B");
+        assert_eq!(Units::Iec.size(1024), "1.00KiB");
+        assert_eq!(Units::Iec.size(1536), "1.50KiB");
+        assert_eq!(Units::Si.size(999), "999B");
+        assert_eq!(Units::Si.size(1000), "1.00kB");
+        assert_eq!(Units::Si.size(1023), "1.02kB");
+        assert_eq!(Units::Si.size(1024), "1.02kB");
+        assert_eq!(Units::Bytes.size(1024), "1024B");
+    }
+
+    #[test]
+    fn sizes_round_up_into_the_next_unit() {
+        // 1023.999KiB would print as 1024.00KiB
+        assert_eq!(Units::Iec.size(KIB * KIB - 1), "1.00MiB");
+        assert_eq!(Units::Iec.size(1023 * KIB + 1000), "1023.98KiB");
+        assert_eq!(Units::Si.size(KB * KB - 1), "1.00MB");
+        assert_eq!(Units::Si.size(999_994), "999.99kB");
+        // no unit after the last one
+        assert_eq!(Units::Iec.size(u64::MAX), "16.00EiB");
+        assert_eq!(Units::Si.size(u64::MAX), "18.45EB");
+    }
+
+    #[test]
+    fn si_and_iec_differ() {
+        assert_eq!(Units::Iec.size(30_000_000), "28.61MiB");
+        assert_eq!(Units::Si.size(30_000_000), "30.00MB");
+        assert_eq!(Units::Iec.rate(1536.0), "1.50KiB/s");
+        assert_eq!(Units::Si.rate(1536.0), "1.54kB/s");
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1023"), Ok(1023));
+        assert_eq!(parse_size("1024B"), Ok(1024));
+        assert_eq!(parse_size("1K"), Ok(1024));
+        assert_eq!(parse_size("1KiB"), Ok(1024));
+        assert_eq!(parse_size("1kB"), Ok(1000));
+        assert_eq!(parse_size("1KB"), Ok(1000));
+        assert_eq!(parse_size("8M"), Ok(8 * 1024 * 1024));
+        assert_eq!(parse_size(" 8 MiB "), Ok(8 * 1024 * 1024));
+        assert_eq!(parse_size("1.5GB"), Ok(1_500_000_000));
+        assert_eq!(parse_size("1.5M"), Ok(1_572_864));
+        assert_eq!(parse_size("2TiB"), Ok(2 << 40));
+        assert_eq!(parse_size("1023.5KiB"), Ok(1_048_064));
+        assert_eq!(parse_size("1023.999KiB"), Ok(1_048_575));
+        assert_eq!(parse_size("4.1GB"), Ok(4_100_000_000));
+        assert_eq!(parse_size("2.3GiB"), Ok(2_469_606_195));
+        assert_eq!(parse_size("0.1MB"), Ok(100_000));
+        assert_eq!(parse_size(".5K"), Ok(512));
+        assert_eq!(parse_size("1.0B"), Ok(1));
+        assert_eq!(parse_size("0.1kB"), Ok(100));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        // not a whole number of bytes
+        assert!(parse_size("1.5").is_err());
+        assert!(parse_size("1.5B").is_err());
+        assert!(parse_size(".").is_err());
+        assert!(parse_size("1.2.3M").is_err());
+        assert!(parse_size("").is_err());
+        assert!(parse_size("MiB").is_err());
+        assert!(parse_size("8XB").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("20000P").is_err());
+        assert!(parse_size("18446744073709551616").is_err());
+        assert_eq!(parse_size("18446744073709551615"), Ok(u64::MAX));
+    }
+}
@@ -1,25 +1,28 @@
-use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::header::HeaderMap;
-use reqwest::multipart;
-use reqwest::{Body, Client, Response};
-use std::error::Error;
-use std::sync::Arc;
-use std::{path::Path};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::path::Path;
 use tokio::fs::File;
-use tokio::io::{AsyncRead, BufReader, AsyncReadExt};
-use tokio::sync::Mutex;
+use tokio::io::{AsyncRead, BufReader, AsyncReadExt, AsyncSeekExt};
+use tokio::sync::{OnceCell, Semaphore};
 use tokio_util::io::ReaderStream;
-use crate::cli::{KindOfUpload};
-use tokio::task;
-use base64::{engine::general_purpose, Engine};
-use serde_json::json;
-use tokio::sync::Semaphore;
-
-const MAX_CONCURRENT_UPLOADS: usize = 32;
+use crate::backend::{Backend, BoxError, ByteStream, FileInfo, Notice, Part, PartBody, PartReceipt, Reporter, Session};
+use crate::crypto::{Encryptor, KeySource, DEFAULT_SEGMENT_SIZE, ENCRYPTED_SUFFIX, TAG_SIZE};
+use crate::error::Error;
+use crate::files::UploadFile;
+use crate::pool::{BufferPool, MemoryBudget};
+use crate::events::{Event, Observer};
+use bytes::Bytes;
+use crate::tuning::{Concurrency, LinkEstimate, Window, MAX_CHUNK_ATTEMPTS};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
 
 struct ProgressReader<R> {
     inner: R,
-    progress: Arc<Mutex<ProgressBar>>,
+    tracker: Tracker,
 }
 
 impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
@@ -34,279 +37,732 @@ impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
             let after = buf.filled().len();
             let bytes_read = after - before;
             if bytes_read > 0 {
-                let progress = self.progress.clone();
-                tokio::spawn(async move {
-                    let pb = progress.lock().await;
-                    pb.inc(bytes_read as u64);
-                });
+                self.tracker.add(bytes_read as u64);
             }
         }
         poll_result
     }
 }
 
+type Observers = Arc<Vec<Arc<dyn Observer>>>;
+
+// how often BytesSent is reported while a file is sent
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+fn emit(observers: &Observers, event: Event) {
+    for observer in observers.iter() {
+        observer.on_event(&event);
+    }
+}
+
+// bytes on disk, counted by readers and chunk tasks
 #[derive(Debug, Default)]
-pub struct Uploader {
-    client: Client,
-    url: String,
-    headers: HeaderMap,
-    kind_of_upload: KindOfUpload,
+struct Counters {
+    // handed to the backend, for BytesSent
+    sent: AtomicU64,
+    acknowledged: AtomicU64,
+    resumed: AtomicU64,
+    first_byte: OnceLock<Duration>,
+    // 0 when the file is streamed as one part
+    total_chunks: AtomicU64,
+    chunks_acknowledged: AtomicU64,
+    chunks_resumed: AtomicU64,
+    chunks_retried: AtomicU64,
 }
 
-impl Uploader {
-    pub fn new(url: &str) -> Self {
+// counts the bytes of one upload for the observers and the result; readers
+// and chunk tasks only add to the counters, `report_while` reads them
+#[derive(Debug, Clone)]
+struct Tracker {
+    file: UploadFile,
+    size: u64,
+    counters: Arc<Counters>,
+    started: Instant,
+    observers: Observers,
+}
+
+impl Tracker {
+    fn new(file: &UploadFile, size: u64, observers: &Observers) -> Self {
         Self {
-            client: Client::builder().build().ok().unwrap(),
-            url: url.to_string(),
-            ..Default::default()
+            file: file.clone(),
+            size,
+            counters: Arc::default(),
+            started: Instant::now(),
+            observers: observers.clone(),
         }
     }
 
-    pub async fn upload_file(
-        self,
-        path: &std::path::Path,
-    ) -> Result<reqwest::Response, Box<dyn Error>> {
-        let file_name = path
-            .file_name()
-            .ok_or("Failed to get file name")?
-            .to_string_lossy()
-            .to_string();
-
-        let mut request = self.client.post(&self.url);
-
-        // Add form to request
-        request = match self.kind_of_upload {
-            KindOfUpload::Multipart => {
-                // Create multipart form with file
-                let async_file = File::open(path).await?;
-                let reader = BufReader::new(async_file);
+    fn is_active(&self) -> bool {
+        !self.observers.is_empty()
+    }
 
-                // convert the reader into a stream
-                let stream = ReaderStream::new(reader);
+    // bytes sent now, the first ones stop the time to first byte
+    fn add(&self, bytes: u64) {
+        if self.counters.first_byte.get().is_none() {
+            let _ = self.counters.first_byte.set(self.started.elapsed());
+        }
+        self.counters.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
 
-                let body = Body::wrap_stream(stream);
+    // bytes the target received and confirmed
+    fn acknowledge(&self, bytes: u64) {
+        self.counters.acknowledged.fetch_add(bytes, Ordering::Relaxed);
+    }
 
-                let part = multipart::Part::stream(body)
-                    .file_name(file_name)
-                    .mime_str("application/octet-stream")?;
-                
+    // bytes the target kept from an earlier attempt
+    fn resume(&self, bytes: u64) {
+        self.counters.sent.fetch_add(bytes, Ordering::Relaxed);
+        self.counters.resumed.fetch_add(bytes, Ordering::Relaxed);
+    }
 
-                let form = multipart::Form::new().part("file", part);
+    // the counts so far, into `result`
+    fn fill(&self, result: &mut FileResult) {
+        let counters = &self.counters;
+        result.acknowledged = counters.acknowledged.load(Ordering::Relaxed);
+        result.resumed = counters.resumed.load(Ordering::Relaxed);
+        result.first_byte = counters.first_byte.get().copied();
+        result.chunks = match counters.total_chunks.load(Ordering::Relaxed) {
+            0 => None,
+            total => Some(ChunkCounts {
+                total,
+                acknowledged: counters.chunks_acknowledged.load(Ordering::Relaxed),
+                resumed: counters.chunks_resumed.load(Ordering::Relaxed),
+                retried: counters.chunks_retried.load(Ordering::Relaxed),
+            }),
+        };
+    }
 
-                request.multipart(form)
+    // runs `transfer`, reporting the count every PROGRESS_INTERVAL and once
+    // more when it is done, so the last BytesSent comes before the result
+    async fn report_while<F: Future>(&self, transfer: F) -> F::Output {
+        if !self.is_active() {
+            return transfer.await;
+        }
+        tokio::pin!(transfer);
+        let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut reported = 0;
+        loop {
+            tokio::select! {
+                output = &mut transfer => {
+                    self.report(&mut reported);
+                    return output;
+                }
+                _ = ticker.tick() => self.report(&mut reported),
             }
-            KindOfUpload::Binary => {
-                // Create form with file
-                let bytes = std::fs::read(path)?;
+        }
+    }
 
-                request.body(bytes)
-            }
-        };
+    fn report(&self, reported: &mut u64) {
+        let sent = self.counters.sent.load(Ordering::Relaxed);
+        if sent == *reported {
+            return;
+        }
+        *reported = sent;
+        self.emit(Event::BytesSent {
+            file: &self.file,
+            sent,
+            size: self.size,
+        });
+    }
 
-        // Add headers to request
-        if !self.headers.is_empty() {
-            request = request.headers(self.headers.clone());
+    fn emit(&self, event: Event) {
+        emit(&self.observers, event);
+    }
+
+    // what the backend reports about the upload of `name`, as events
+    fn reporter(&self, name: &str) -> Reporter {
+        if !self.is_active() {
+            return Reporter::default();
         }
+        let tracker = self.clone();
+        let name = name.to_string();
+        Reporter::new(move |notice| match notice {
+            Notice::Warning(message) => tracker.emit(Event::Warning {
+                file: &tracker.file,
+                message,
+            }),
+            Notice::Resumable { location } => tracker.emit(Event::Resumable {
+                file: &tracker.file,
+                name: &name,
+                location,
+            }),
+        })
+    }
+}
 
-        // Send request
-        let response = request.send().await?;
+/// A file that made it to the target.
+#[derive(Debug, Clone)]
+pub struct UploadReport {
+    /// The name the server sees, with the .enc suffix when encrypted.
+    pub name: String,
+    /// What the backend reported, e.g. a download link.
+    pub response: Option<String>,
+}
 
-        // Check if request was successful
-        if response.status().is_success() {
-            Ok(response)
-        } else {
-            Err(format!("Request failed with status: {}", response.status()).into())
+/// How the upload of one file ended.
+#[derive(Debug)]
+pub enum Outcome {
+    Uploaded(UploadReport),
+    Failed(Error),
+    /// Skipped after a shutdown.
+    NotStarted,
+}
+
+/// What became of one file.
+#[derive(Debug)]
+pub struct FileResult {
+    pub file: UploadFile,
+    /// Bytes on disk, 0 when it couldn't be read.
+    pub size: u64,
+    /// From the start of the upload until its outcome.
+    pub elapsed: Duration,
+    /// From the start until the first bytes were sent, for chunked uploads
+    /// until the first chunk was accepted; None when nothing was sent.
+    pub first_byte: Option<Duration>,
+    /// Bytes on disk the target confirmed in this run, also of failed files.
+    pub acknowledged: u64,
+    /// Bytes on disk the target kept from an earlier run, not sent again.
+    pub resumed: u64,
+    /// None when the file was streamed as one part.
+    pub chunks: Option<ChunkCounts>,
+    pub outcome: Outcome,
+}
+
+/// The chunks of one file.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChunkCounts {
+    pub total: u64,
+    /// Accepted by the target in this run.
+    pub acknowledged: u64,
+    /// Kept by the target from an earlier run.
+    pub resumed: u64,
+    /// Failed attempts that were sent again.
+    pub retried: u64,
+}
+
+/// Uploads files through a [`Backend`], see [`crate::backend::build`].
+///
+/// Cheap to clone, clones share the backend and with it the HTTP client and
+/// its connection pool.
+///
+/// ```no_run
+/// # async fn run(backend: std::sync::Arc<dyn dup_cli::backend::Backend>) {
+/// use dup_cli::{files, Outcome, Uploader};
+///
+/// let uploader = Uploader::builder(backend).max_memory(256 << 20).build();
+/// let files = files::collect(&["photos"], |path, e| eprintln!("{}: {}", path.display(), e));
+/// for result in uploader.upload_all(files, 4).await {
+///     if let Outcome::Failed(e) = &result.outcome {
+///         eprintln!("{}: {}", result.file.path.display(), e);
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Uploader {
+    backend: Arc<dyn Backend>,
+    key_source: Option<KeySource>,
+    observers: Observers,
+    // --chunk-size auto, sized from what `link` measured after probing this host
+    auto_chunk_size: Option<String>,
+    probed: Arc<OnceCell<()>>,
+    chunk_concurrency: Option<Concurrency>,
+    link: Arc<LinkEstimate>,
+    // chunk buffers of all files together, unbounded when unset
+    memory: Option<MemoryBudget>,
+    // cancelled on Ctrl-C, running uploads stop at the next safe point
+    shutdown: CancellationToken,
+}
+
+/// Configures an [`Uploader`], everything is off or the backend's choice unless set.
+#[derive(Debug)]
+pub struct UploaderBuilder {
+    uploader: Uploader,
+}
+
+impl UploaderBuilder {
+    /// Seals files with a fresh encryptor for this key before they leave the machine.
+    pub fn key_source(mut self, key_source: KeySource) -> Self {
+        self.uploader.key_source = Some(key_source);
+        self
+    }
+
+    /// Sends the [`Event`]s of every upload to `observer`, in addition to the ones added before.
+    pub fn observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        Arc::make_mut(&mut self.uploader.observers).push(observer);
+        self
+    }
+
+    /// Picks chunk sizes from the round trip time to `host` and the throughput of earlier chunks.
+    pub fn auto_chunk_size(mut self, host: impl Into<String>) -> Self {
+        self.uploader.auto_chunk_size = Some(host.into());
+        self
+    }
+
+    /// Chunks of one file in flight, the backend's limit when unset.
+    pub fn chunk_concurrency(mut self, concurrency: Concurrency) -> Self {
+        self.uploader.chunk_concurrency = Some(concurrency);
+        self
+    }
+
+    /// Caps the chunk buffers of all files together at `bytes`.
+    pub fn max_memory(mut self, bytes: u64) -> Self {
+        self.uploader.memory = Some(MemoryBudget::new(bytes));
+        self
+    }
+
+    /// Once `shutdown` is cancelled, no new files start, chunks in flight are
+    /// finished, streams are dropped and the upload is aborted like a failed one.
+    pub fn shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.uploader.shutdown = shutdown;
+        self
+    }
+
+    pub fn build(self) -> Uploader {
+        self.uploader
+    }
+}
+
+impl Uploader {
+    pub fn new(backend: Arc<dyn Backend>) -> Self {
+        Self::builder(backend).build()
+    }
+
+    pub fn builder(backend: Arc<dyn Backend>) -> UploaderBuilder {
+        UploaderBuilder {
+            uploader: Self {
+                backend,
+                key_source: None,
+                observers: Arc::new(Vec::new()),
+                auto_chunk_size: None,
+                probed: Arc::new(OnceCell::new()),
+                chunk_concurrency: None,
+                link: Arc::new(LinkEstimate::default()),
+                memory: None,
+                shutdown: CancellationToken::new(),
+            },
         }
     }
 
-    pub async fn upload_file_with_progress(self, path: &Path) -> Result<Response, Box<dyn Error>> {
+    // the name the server sees, encrypted uploads get the .enc suffix
+    pub fn upload_name(&self, path: &Path) -> Result<String, BoxError> {
         let file_name = path
             .file_name()
             .ok_or("Failed to get file name")?
             .to_string_lossy()
             .to_string();
 
-        let file_size = std::fs::metadata(path)?.len();
-
-        // create a progress bar
-        let progress_bar = Arc::new(Mutex::new(ProgressBar::new(file_size)));
-        {
-            let pb = progress_bar.lock().await;
-            pb.set_style(
-                ProgressStyle::default_bar()
-                    .template(
-                        "[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} ({eta})",
-                    )
-                    .unwrap()
-                    .progress_chars("##-"),
-            );
+        if self.key_source.is_some() {
+            Ok(format!("{}{}", file_name, ENCRYPTED_SUFFIX))
+        } else {
+            Ok(file_name)
         }
+    }
 
-        let mut request = self.client.post(&self.url);
-
-        // Add form to request
-        request = match self.kind_of_upload {
-            KindOfUpload::Multipart => {
-                // open file for async reading
-                let async_file = File::open(path).await?;
-                let reader = BufReader::new(async_file);
+    /// Uploads `files`, up to `jobs` at a time, and returns their results in the same order.
+    pub async fn upload_all(&self, files: Vec<UploadFile>, jobs: usize) -> Vec<FileResult> {
+        // bounded so that connections are reused instead of opening one per file
+        let jobs = Arc::new(Semaphore::new(jobs.max(1)));
+        let mut handles = vec![];
 
-                // wrap the stream in a progress reader
-                let progress_reader = ProgressReader {
-                    inner: reader,
-                    progress: progress_bar.clone(),
-                };
+        for file in &files {
+            emit(&self.observers, Event::Queued { file });
+        }
 
-                // convert async reader into a stream
-                let stream =
-                    ReaderStream::new(progress_reader);
+        // files start in the given order
+        for file in files {
+            let permit = jobs.clone().acquire_owned().await.unwrap();
+            let uploader = self.clone();
+            handles.push(tokio::spawn(async move {
+                let result = uploader.upload(&file).await;
+                drop(permit);
+                result
+            }));
+        }
 
-                let body = Body::wrap_stream(stream);
+        let mut results = Vec::with_capacity(handles.len());
+        for handle in handles {
+            results.push(handle.await.unwrap());
+        }
+        results
+    }
 
-                let part = multipart::Part::stream(body)
-                    .file_name(file_name)
-                    .mime_str("application/octet-stream")?;
+    /// Uploads one file, unless the shutdown token was already cancelled.
+    pub async fn upload(&self, file: &UploadFile) -> FileResult {
+        let time = Instant::now();
+        let mut result = FileResult {
+            file: file.clone(),
+            size: 0,
+            elapsed: Duration::ZERO,
+            first_byte: None,
+            acknowledged: 0,
+            resumed: 0,
+            chunks: None,
+            outcome: Outcome::NotStarted,
+        };
 
-                let form = multipart::Form::new().part("file", part);
+        let metadata = std::fs::metadata(&file.path);
+        if let Ok(metadata) = &metadata {
+            result.size = metadata.len();
+        }
+        // no new files once interrupted
+        if !self.shutdown.is_cancelled() {
+            result.outcome = match metadata {
+                Ok(_) => {
+                    let tracker = Tracker::new(file, result.size, &self.observers);
+                    let outcome = match self.put_file(file, result.size, &tracker).await {
+                        Ok(report) => Outcome::Uploaded(report),
+                        Err(e) => Outcome::Failed(e.into()),
+                    };
+                    tracker.fill(&mut result);
+                    outcome
+                }
+                Err(e) => Outcome::Failed(e.into()),
+            };
+        }
+        result.elapsed = time.elapsed();
 
-                request.multipart(form)
-            }
-            KindOfUpload::Binary => {
-                // Create form with file
-                let bytes = std::fs::read(path)?;
+        match &result.outcome {
+            Outcome::Uploaded(_) => emit(&self.observers, Event::Finished { result: &result }),
+            Outcome::Failed(error) => emit(&self.observers, Event::Failed { result: &result, error }),
+            Outcome::NotStarted => emit(&self.observers, Event::Skipped { file }),
+        }
+        result
+    }
 
-                request.body(bytes)
-            }
+    async fn put_file(
+        &self,
+        upload_file: &UploadFile,
+        file_size: u64,
+        tracker: &Tracker,
+    ) -> Result<UploadReport, BoxError> {
+        let path = &upload_file.path;
+        if let Some(host) = &self.auto_chunk_size {
+            self.probed.get_or_init(|| self.link.probe_rtt(host)).await;
+        }
+        let preferred = self.auto_chunk_size.is_some().then(|| self.link.chunk_size(file_size));
+        let chunk_size = self.backend.chunk_size(file_size, preferred);
+
+        // chunked backends seal each chunk as one envelope segment
+        let encryptor = match &self.key_source {
+            Some(key_source) => Some(Arc::new(Encryptor::new(
+                key_source,
+                chunk_size.unwrap_or(DEFAULT_SEGMENT_SIZE),
+            )?)),
+            None => None,
         };
 
-        // Add headers to request
-        if !self.headers.is_empty() {
-            request = request.headers(self.headers.clone());
+        let name = self.upload_name(path)?;
+        let reporter = tracker.reporter(&name);
+        let file = Arc::new(FileInfo {
+            path: path.to_path_buf(),
+            name,
+            relative_path: upload_file.relative_path.clone(),
+            size: match &encryptor {
+                Some(encryptor) => encryptor.encrypted_len(file_size),
+                None => file_size,
+            },
+            chunk_size: chunk_size.map(|chunk_size| match &encryptor {
+                Some(_) => (chunk_size + TAG_SIZE) as u64,
+                None => chunk_size as u64,
+            }),
+            header_size: encryptor.as_ref().map_or(0, |encryptor| encryptor.header().len() as u64),
+            resumable: encryptor.is_none(),
+            reporter,
+        });
+
+        tracker.emit(Event::Started {
+            file: upload_file,
+            name: &file.name,
+            size: file_size,
+            chunk_size,
+            total_chunks: match chunk_size {
+                Some(chunk_size) => total_chunks(file_size, chunk_size, encryptor.as_deref()),
+                None => 1,
+            },
+        });
+
+        let session = self.backend.init(&file).await?;
+        if session.offset > 0 {
+            tracker.emit(Event::Resumed {
+                file: upload_file,
+                offset: session.offset,
+            });
         }
 
-        // Send request
-        let response = request.send().await?;
+        let result = match chunk_size {
+            Some(chunk_size) => {
+                tracker
+                    .report_while(self.put_chunks(&file, &session, file_size, chunk_size, encryptor, tracker.clone()))
+                    .await
+            }
+            None => {
+                tracker
+                    .report_while(self.put_whole(&file, &session, file_size, encryptor, tracker.clone()))
+                    .await
+            }
+        };
 
-        // finish progress bar
-        {
-            let pb = progress_bar.lock().await;
-            let msg = format!("Uploaded {} bytes", pb.position());
-            pb.finish_with_message(msg);
-        }
+        let result = match result {
+            Ok(receipts) => self.backend.complete(&file, &session, receipts).await,
+            Err(e) => Err(e),
+        };
 
-        // Check if request was successful
-        if response.status().is_success() {
-            Ok(response)
-        } else {
-            Err(format!("Request failed with status: {}", response.status()).into())
+        if result.is_err() {
+            // leave nothing half uploaded behind, the upload error is what matters
+            if let Err(e) = self.backend.abort(&file, &session).await {
+                tracker.emit(Event::Warning {
+                    file: upload_file,
+                    message: &format!("failed to abort upload of {}: {}", file.name, e),
+                });
+            }
         }
-    }
 
-    pub fn add_headers(&mut self, headers: HeaderMap) {
-        self.headers = headers;
+        Ok(UploadReport {
+            name: file.name.clone(),
+            response: result?,
+        })
     }
 
-    pub fn add_header(&mut self, key: String, value: String) -> Result<(), Box<dyn Error>> {
-        self.headers.insert(
-            reqwest::header::HeaderName::from_bytes(key.as_bytes())?,
-            reqwest::header::HeaderValue::from_str(&value)?,
-        );
-        Ok(())
+    // streams the file as a single part
+    async fn put_whole(
+        &self,
+        file: &FileInfo,
+        session: &Session,
+        file_size: u64,
+        encryptor: Option<Arc<Encryptor>>,
+        tracker: Tracker,
+    ) -> Result<Vec<PartReceipt>, BoxError> {
+        let mut handle = File::open(&file.path).await?;
+
+        // continue after what the target kept, sealed files always start over
+        let offset = match &encryptor {
+            Some(_) => 0,
+            None => session.offset.min(file_size),
+        };
+        if offset > 0 {
+            handle.seek(std::io::SeekFrom::Start(offset)).await?;
+            tracker.resume(offset);
+        }
+        let reader = ProgressReader {
+            inner: BufReader::new(handle),
+            tracker: tracker.clone(),
+        };
+        let body = byte_stream(reader, file_size, encryptor);
+
+        let part = Part {
+            index: 0,
+            total: 1,
+            offset,
+            len: file.size - offset,
+            body: PartBody::Stream(body),
+        };
+
+        // the target keeps what arrived, e.g. for tus and FTP to resume from
+        let receipt = tokio::select! {
+            receipt = self.backend.put_part(file, session, part) => receipt?,
+            _ = self.shutdown.cancelled() => return Err(Error::Cancelled.into()),
+        };
+        tracker.acknowledge(file_size - offset);
+        Ok(vec![receipt])
     }
 
-    pub async fn upload_file_with_chunk_size(
+    // reads, seals and sends chunks concurrently, as many as the window allows
+    async fn put_chunks(
         &self,
-        path: &std::path::Path,
+        file: &Arc<FileInfo>,
+        session: &Session,
+        file_size: u64,
         chunk_size: usize,
-    ) -> Result<(), Box<dyn Error>> {
+        encryptor: Option<Arc<Encryptor>>,
+        tracker: Tracker,
+    ) -> Result<Vec<PartReceipt>, BoxError> {
+        let total_chunks = total_chunks(file_size, chunk_size, encryptor.as_deref());
+        tracker.counters.total_chunks.store(total_chunks, Ordering::Relaxed);
+
+        let mut handle = File::open(&file.path).await?;
+        let header_len = encryptor.as_ref().map_or(0, |encryptor| encryptor.header().len() as u64);
+        let sealed_chunk_size = match &encryptor {
+            Some(_) => (chunk_size + TAG_SIZE) as u64,
+            None => chunk_size as u64,
+        };
 
-        let file_metadata = std::fs::metadata(path)?;
-        let file_size = file_metadata.len();
-        let file_name = path
-            .file_name()
-            .ok_or("Failed to get file name")?
-            .to_string_lossy()
-            .to_string();
+        // sized for the largest chunk, the first one with the envelope header
+        let mut pool = BufferPool::new((sealed_chunk_size + header_len) as usize, self.memory.clone())?;
+        let recycler = pool.recycler();
+
+        let mut window = Window::new(self.chunk_concurrency, self.backend.max_concurrency());
+        let mut tasks = JoinSet::new();
+        let mut receipts = Vec::with_capacity(total_chunks as usize);
+        let mut chunks = 0..total_chunks;
+        let mut retries = VecDeque::new();
+        let mut attempts = HashMap::new();
+
+        loop {
+            // chunks are started in order, so a backend that takes one
+            // chunk at a time receives them sequentially
+            while tasks.len() < window.limit() && !self.shutdown.is_cancelled() {
+                let Some(chunk_id) = retries.pop_front().or_else(|| chunks.next()) else {
+                    break;
+                };
 
-        println!("Uploading file {} with chunk size {}", file_name, chunk_size);
-
-        let total_chunks = file_size.div_ceil(chunk_size as u64);
-
-        let file = Arc::new(tokio::sync::Mutex::new(File::open(path).await?));
-        let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_UPLOADS));
-
-        let mut tasks = vec![];
-
-        let client = self.client.clone();
-        let url = self.url.clone();
-
-        for chunk_id in 0..total_chunks {
-            let filename_clone = file_name.clone();
-            let client_clone = client.clone();
-            let url_clone = url.clone();
-            let file_clone = Arc::clone(&file);
-            let semaphore_clone = Arc::clone(&semaphore);
-
-            let task = task::spawn(async move {
-                
-                // limit concurrent uploads
-                let _permit = semaphore_clone.acquire().await.unwrap();
-
-                let mut file = file_clone.lock().await;
-                let mut buffer = vec![0; chunk_size];
-
-                // file.seek(SeekFrom::Start(chunk_id * chunk_size as u64)).await.unwrap();
-                // read chunk manually using read_exact where possible
-                let start_offset = chunk_id * chunk_size as u64;
-                let read_pos = Arc::new(Mutex::new(start_offset));
-                let mut bytes_read = 0;
-                while bytes_read < chunk_size {
-                    match file.read(&mut buffer[bytes_read..]).await {
-                        Ok(0) => break,
-                        Ok(n) => {
-                            bytes_read += n;
-                            let mut read_pos = read_pos.lock().await;
-                            *read_pos += n as u64;
-                        }
-                        Err(e) => {
-                            eprintln!("Error reading file: {:?}", e);
-                            break;
-                        }
-                    }
+                // chunks the target kept from an earlier attempt, the empty chunk
+                // of an empty file is always sent
+                let chunk_end = if chunk_id + 1 == total_chunks {
+                    file.size
+                } else {
+                    (chunk_id + 1) * sealed_chunk_size + header_len
+                };
+                if session.offset > 0 && chunk_end <= session.offset {
+                    let plain_len = (file_size - chunk_id * chunk_size as u64).min(chunk_size as u64);
+                    tracker.resume(plain_len);
+                    tracker.counters.chunks_resumed.fetch_add(1, Ordering::Relaxed);
+                    tracker.emit(Event::ChunkDone {
+                        file: &tracker.file,
+                        index: chunk_id,
+                        total: total_chunks,
+                        response: None,
+                        resumed: true,
+                    });
+                    receipts.push(PartReceipt {
+                        index: chunk_id,
+                        ..Default::default()
+                    });
+                    continue;
                 }
 
-                buffer.truncate(bytes_read); // remove extra unused bytes
-                 
-                // Encode chunk in Base64 (required for JSON compatibility)
-                let encoded_data = general_purpose::STANDARD.encode(&buffer);
+                let attempt = attempts.entry(chunk_id).or_insert(0u32);
+                *attempt += 1;
+                let attempt = *attempt;
+
+                // read in order here, sealed and sent by the task; waits for a
+                // buffer to come back when --max-memory is spent
+                let mut buffer = pool.get().await?;
+                handle.seek(std::io::SeekFrom::Start(chunk_id * chunk_size as u64)).await?;
+                (&mut handle).take(chunk_size as u64).read_to_end(&mut buffer).await?;
+                let plain_len = buffer.len() as u64;
+
+                let backend = self.backend.clone();
+                let link = self.link.clone();
+                let file = file.clone();
+                let session = session.clone();
+                let encryptor = encryptor.clone();
+                let tracker = tracker.clone();
+                let recycler = recycler.clone();
+
+                tasks.spawn(async move {
+                    // chunk N is sealed as segment N, the first one carries the
+                    // envelope header so the reassembled file decrypts as a whole
+                    let mut offset = chunk_id * sealed_chunk_size;
+                    let sealed = match &encryptor {
+                        Some(encryptor) => {
+                            let sealed = encryptor.encrypt_segment(chunk_id, chunk_id + 1 == total_chunks, &mut buffer);
+                            if chunk_id == 0 {
+                                buffer.splice(0..0, encryptor.header().iter().copied());
+                            } else {
+                                offset += header_len;
+                            }
+                            sealed
+                        }
+                        None => Ok(()),
+                    };
+                    let data = Bytes::from(buffer);
+
+                    let result = async {
+                        sealed?;
+                        if attempt > 1 {
+                            tokio::time::sleep(Duration::from_millis(500 * 2u64.pow(attempt - 2))).await;
+                        }
 
-                let payload = json!({
-                    "filename": filename_clone,
-                    "chunk_id": chunk_id,
-                    "total_chunks": total_chunks,
-                    "data": encoded_data,
+                        let len = data.len() as u64;
+                        let part = Part {
+                            index: chunk_id,
+                            total: total_chunks,
+                            offset,
+                            len,
+                            body: PartBody::Bytes(data.clone()),
+                        };
+                        let started = Instant::now();
+                        let receipt = backend.put_part(&file, &session, part).await?;
+                        link.record(len, started.elapsed());
+
+                        tracker.add(plain_len);
+                        tracker.acknowledge(plain_len);
+                        tracker.counters.chunks_acknowledged.fetch_add(1, Ordering::Relaxed);
+                        tracker.emit(Event::ChunkDone {
+                            file: &tracker.file,
+                            index: chunk_id,
+                            total: total_chunks,
+                            response: receipt.response.as_deref(),
+                            resumed: false,
+                        });
+
+                        Ok::<_, BoxError>((receipt, len))
+                    };
+                    let result = result.await;
+                    // failed or not, so the pool never runs dry
+                    recycler.recycle(data);
+                    (chunk_id, result)
                 });
+            }
 
-
-                let response = client_clone
-                    .post(url_clone)
-                    .json(&payload)
-                    .send()
-                    .await
-                    .unwrap();
-
-                println!("Chunk {} of {} uploaded: {:?}", chunk_id + 1, total_chunks, response.text().await.unwrap());
-            });
-
-            tasks.push(task);
+            // wait for a free slot, and stop starting chunks once one has failed
+            let Some(result) = tasks.join_next().await else {
+                break;
+            };
+            let (chunk_id, result) = result?;
+            match result.map_err(Error::from) {
+                Ok((receipt, len)) => {
+                    window.on_success(len);
+                    receipts.push(receipt);
+                }
+                // with an adaptive window a failure means backing off, not giving
+                // up, unless sending the chunk again can't help
+                Err(e) if window.adaptive() && e.is_retryable() && attempts[&chunk_id] < MAX_CHUNK_ATTEMPTS => {
+                    window.on_error();
+                    tracker.counters.chunks_retried.fetch_add(1, Ordering::Relaxed);
+                    tracker.emit(Event::Retried {
+                        file: &tracker.file,
+                        index: chunk_id,
+                        attempt: attempts[&chunk_id],
+                        error: &e,
+                        in_flight: window.limit(),
+                    });
+                    retries.push_back(chunk_id);
+                }
+                Err(e) => {
+                    // stop the remaining chunks, the upload is aborted anyway
+                    tasks.abort_all();
+                    return Err(e.into());
+                }
+            }
         }
-
-        for task in tasks {
-            task.await.unwrap();
+        // interrupted, the chunks that were in flight are done
+        if receipts.len() < total_chunks as usize {
+            return Err(Error::Cancelled.into());
         }
+        receipts.sort_by_key(|receipt| receipt.index);
+
+        Ok(receipts)
+    }
+}
+
+// an empty file is still sent as one empty chunk
+fn total_chunks(file_size: u64, chunk_size: usize, encryptor: Option<&Encryptor>) -> u64 {
+    match encryptor {
+        Some(encryptor) => encryptor.total_segments(file_size),
+        None => file_size.div_ceil(chunk_size as u64).max(1),
+    }
+}
 
-        println!("File {} uploaded successfully", file_name);
-        
-        Ok(())
+// the file as a stream of bytes, sealed when an encryptor is given
+fn byte_stream<R>(reader: R, file_size: u64, encryptor: Option<Arc<Encryptor>>) -> ByteStream
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    match encryptor {
+        Some(encryptor) => Box::pin(encryptor.encrypt_stream(reader, file_size)),
+        None => Box::pin(ReaderStream::new(reader)),
     }
 }
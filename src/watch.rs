@@ -0,0 +1,376 @@
+use crate::cli::WatchArgs;
+use crate::ui::JsonLines;
+use crate::{EXIT_INTERRUPTED, EXIT_IO};
+use dup_cli::{files, FileResult, Outcome, UploadFile, Uploader};
+use notify::event::{AccessKind, AccessMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+// how often pending files are checked for being complete
+const TICK: Duration = Duration::from_millis(500);
+// failed uploads are tried again after this, doubled for every failure
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+// a file that was written to and is not uploaded yet
+struct Pending {
+    stat: Option<Stat>,
+    // when the size or modification time last changed
+    changed: Instant,
+    // closed after writing, complete unless it is opened again
+    closed: bool,
+    // failed uploads since the file last changed
+    attempts: u32,
+    retry_at: Option<Instant>,
+}
+
+// what a file looked like when it was last seen or uploaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stat {
+    size: u64,
+    modified: u64,
+}
+
+impl Stat {
+    fn new(metadata: &std::fs::Metadata) -> Self {
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        Self {
+            size: metadata.len(),
+            modified: modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        }
+    }
+
+    fn of(path: &Path) -> Option<Self> {
+        std::fs::metadata(path).ok().filter(|metadata| metadata.is_file()).map(|metadata| Self::new(&metadata))
+    }
+}
+
+// the files uploaded by earlier runs, with their size and modification time
+// at the time, so a restart only uploads files that are new or changed since
+struct State {
+    path: PathBuf,
+    // `<host> <path>`, the same folder may be watched for several hosts
+    host: String,
+    uploaded: Map<String, Value>,
+}
+
+impl State {
+    fn default_file() -> Option<PathBuf> {
+        dirs::cache_dir().map(|dir| dir.join("dup-cli").join("watch.json"))
+    }
+
+    fn load(path: PathBuf, host: &str) -> Self {
+        let uploaded = std::fs::read(&path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Self {
+            path,
+            host: host.to_string(),
+            uploaded,
+        }
+    }
+
+    fn key(&self, path: &Path) -> String {
+        format!("{} {}", self.host, path.display())
+    }
+
+    fn get(&self, path: &Path) -> Option<Stat> {
+        let entry = self.uploaded.get(&self.key(path))?;
+        Some(Stat {
+            size: entry.get("size")?.as_u64()?,
+            modified: entry.get("modified")?.as_u64()?,
+        })
+    }
+
+    fn record(&mut self, path: &Path, stat: Stat) -> std::io::Result<()> {
+        let key = self.key(path);
+        self.uploaded.insert(key, json!({ "size": stat.size, "modified": stat.modified }));
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, Value::Object(self.uploaded.clone()).to_string())
+    }
+}
+
+struct Watch<'a> {
+    args: &'a WatchArgs,
+    // the watched paths, canonical
+    roots: Vec<PathBuf>,
+    pending: HashMap<PathBuf, Pending>,
+    running: HashSet<PathBuf>,
+    state: Option<State>,
+    uploaded: usize,
+    failed: usize,
+}
+
+impl Watch<'_> {
+    // the place of a file below the target, the same as when its folder is uploaded
+    fn upload_file(&self, path: &Path) -> Option<UploadFile> {
+        let root = self.roots.iter().filter(|root| path.starts_with(root)).max_by_key(|root| root.as_os_str().len())?;
+        let name = PathBuf::from(root.file_name().unwrap_or_default());
+        let relative_path = match path.strip_prefix(root) {
+            Ok(rest) if !rest.as_os_str().is_empty() => name.join(rest),
+            _ => name,
+        };
+        Some(UploadFile {
+            path: path.to_path_buf(),
+            relative_path,
+        })
+    }
+
+    // a file was written to, it is uploaded once it is complete
+    fn queue(&mut self, path: PathBuf, closed: bool) {
+        if path.is_dir() {
+            // moved in folders come with no events for their files
+            return self.scan(&[path]);
+        }
+        let stat = Stat::of(&path);
+        let pending = self.pending.entry(path).or_insert(Pending {
+            stat,
+            changed: Instant::now(),
+            closed,
+            attempts: 0,
+            retry_at: None,
+        });
+        if pending.stat != stat {
+            pending.stat = stat;
+            pending.changed = Instant::now();
+            pending.attempts = 0;
+            pending.retry_at = None;
+        }
+        pending.closed = closed;
+    }
+
+    fn scan(&mut self, paths: &[PathBuf]) {
+        let files = files::collect(paths, |path, e| eprintln!("Error: {}: {}", path.display(), e));
+        for file in files {
+            self.queue(file.path, false);
+        }
+    }
+
+    // the pending files that are complete: closed after writing, or unchanged for --settle
+    fn ready(&mut self) -> Vec<(PathBuf, Stat)> {
+        let settle = Duration::from_secs(self.args.settle);
+        let now = Instant::now();
+        let mut ready = vec![];
+        let mut gone = vec![];
+        for (path, pending) in &mut self.pending {
+            let stat = match Stat::of(path) {
+                Some(stat) => stat,
+                None => {
+                    gone.push(path.clone());
+                    continue;
+                }
+            };
+            if pending.stat != Some(stat) {
+                pending.stat = Some(stat);
+                pending.changed = now;
+                pending.closed = false;
+            }
+            let complete = pending.closed || now.duration_since(pending.changed) >= settle;
+            let due = pending.retry_at.is_none_or(|retry_at| now >= retry_at);
+            if complete && due && !self.running.contains(path) {
+                ready.push((path.clone(), stat));
+            }
+        }
+        for path in gone {
+            self.pending.remove(&path);
+        }
+        ready
+    }
+
+    // whether a complete file is to be uploaded, otherwise it is no longer pending
+    fn wanted(&mut self, path: &Path, stat: Stat) -> bool {
+        let selected = match std::fs::metadata(path) {
+            Ok(metadata) => self.args.upload.filter.check(path, &metadata).is_ok(),
+            Err(_) => false,
+        };
+        let uploaded = self.state.as_ref().and_then(|state| state.get(path)) == Some(stat);
+        if !selected || uploaded {
+            self.pending.remove(path);
+            return false;
+        }
+        true
+    }
+
+    fn finished(&mut self, path: PathBuf, stat: Stat, result: FileResult) {
+        self.running.remove(&path);
+        match &result.outcome {
+            Outcome::Uploaded(_) => {
+                self.uploaded += 1;
+                // written again while it was uploaded, the new content is sent when complete
+                let changed = self.pending.get(&path).is_some_and(|pending| pending.stat != Some(stat));
+                if !changed {
+                    self.pending.remove(&path);
+                }
+                if let Some(state) = &mut self.state {
+                    if let Err(e) = state.record(&path, stat) {
+                        eprintln!("Warning: failed to record the upload of {}: {}", path.display(), e);
+                    }
+                }
+            }
+            Outcome::Failed(e) if e.is_retryable() => {
+                self.failed += 1;
+                if let Some(pending) = self.pending.get_mut(&path) {
+                    let delay = RETRY_DELAY.saturating_mul(2u32.saturating_pow(pending.attempts)).min(MAX_RETRY_DELAY);
+                    pending.attempts += 1;
+                    pending.retry_at = Some(Instant::now() + delay);
+                    if !self.args.upload.json {
+                        eprintln!("Retrying {} in {}s", path.display(), delay.as_secs());
+                    }
+                }
+            }
+            // cancelled by Ctrl-C, or failed for a reason that needs the file to change
+            Outcome::Failed(e) => {
+                if !matches!(e, dup_cli::Error::Cancelled) {
+                    self.failed += 1;
+                }
+                self.pending.remove(&path);
+            }
+            Outcome::NotStarted => {}
+        }
+    }
+}
+
+// uploads new and changed files below the watched paths until Ctrl-C, returns the exit status
+pub async fn run(args: &WatchArgs, uploader: Uploader, shutdown: CancellationToken) -> i32 {
+    let mut roots = vec![];
+    for path in &args.upload.paths {
+        match std::fs::canonicalize(path) {
+            Ok(root) => roots.push(root),
+            Err(e) => {
+                eprintln!("Error: {}: {}", path.display(), e);
+                return EXIT_IO;
+            }
+        }
+    }
+
+    // notify calls back from its own thread
+    let (sender, mut events) = mpsc::unbounded_channel();
+    let mut watcher = match notify::recommended_watcher(move |event| {
+        let _ = sender.send(event);
+    }) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            eprintln!("Error: failed to watch for changes: {}", e);
+            return EXIT_IO;
+        }
+    };
+    for root in &roots {
+        if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+            eprintln!("Error: failed to watch {}: {}", root.display(), e);
+            return EXIT_IO;
+        }
+    }
+
+    let state = args
+        .state
+        .clone()
+        .or_else(State::default_file)
+        .map(|path| State::load(path, &args.upload.host));
+    let mut watch = Watch {
+        args,
+        roots,
+        pending: HashMap::new(),
+        running: HashSet::new(),
+        state,
+        uploaded: 0,
+        failed: 0,
+    };
+
+    if args.upload.json {
+        let paths: Vec<String> = watch.roots.iter().map(|root| root.display().to_string()).collect();
+        JsonLines::print(json!({ "event": "watching", "paths": paths }));
+    } else {
+        let paths: Vec<String> = watch.roots.iter().map(|root| root.display().to_string()).collect();
+        println!("Watching {} for new and changed files, press Ctrl-C to stop", paths.join(", "));
+    }
+
+    // files written while nothing watched, those uploaded before are skipped by the state
+    let roots = watch.roots.clone();
+    watch.scan(&roots);
+
+    // one file at a time with progress bars, those of parallel uploads would interleave
+    let jobs = if args.upload.progress { 1 } else { args.upload.jobs };
+    let mut uploads = JoinSet::new();
+    let mut tick = tokio::time::interval(TICK);
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            Some(event) = events.recv() => match event {
+                Ok(event) if event.need_rescan() => watch.scan(&roots),
+                Ok(event) => match event.kind {
+                    EventKind::Create(_) | EventKind::Modify(_) => {
+                        for path in event.paths {
+                            watch.queue(path, false);
+                        }
+                    }
+                    EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                        for path in event.paths {
+                            watch.queue(path, true);
+                        }
+                    }
+                    _ => {}
+                },
+                Err(e) => eprintln!("Error: watching for changes: {}", e),
+            },
+            Some(done) = uploads.join_next(), if !uploads.is_empty() => {
+                let (path, stat, result) = done.unwrap();
+                watch.finished(path, stat, result);
+            }
+            _ = tick.tick() => {
+                for (path, stat) in watch.ready() {
+                    if uploads.len() >= jobs {
+                        break;
+                    }
+                    if !watch.wanted(&path, stat) {
+                        continue;
+                    }
+                    let Some(file) = watch.upload_file(&path) else {
+                        watch.pending.remove(&path);
+                        continue;
+                    };
+                    watch.running.insert(path.clone());
+                    let uploader = uploader.clone();
+                    uploads.spawn(async move {
+                        let result = uploader.upload(&file).await;
+                        (path, stat, result)
+                    });
+                }
+            }
+        }
+    }
+
+    // running uploads stop at the next safe point
+    while let Some(done) = uploads.join_next().await {
+        let (path, stat, result) = done.unwrap();
+        watch.finished(path, stat, result);
+    }
+
+    if args.upload.json {
+        JsonLines::print(json!({
+            "event": "summary",
+            "uploaded": watch.uploaded,
+            "failed": watch.failed,
+            "pending": watch.pending.len(),
+            "interrupted": true,
+        }));
+    } else {
+        println!(
+            "Stopped watching: {} files uploaded, {} failed, {} pending",
+            watch.uploaded,
+            watch.failed,
+            watch.pending.len()
+        );
+    }
+    EXIT_INTERRUPTED
+}